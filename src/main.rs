@@ -1,23 +1,24 @@
 use crossterm::{
-    event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyEvent},
+    event::{self, DisableMouseCapture, EnableMouseCapture, Event, KeyCode, KeyEvent, KeyEventKind, KeyModifiers, MouseButton, MouseEventKind},
     execute,
     terminal::{disable_raw_mode, enable_raw_mode, EnterAlternateScreen, LeaveAlternateScreen},
 };
 use ratatui::{
     backend::CrosstermBackend,
-    layout::{Constraint, Direction, Layout},
-    style::{Color, Style},
-    text::Span,
-    widgets::{Block, Borders, Paragraph},
+    layout::{Alignment, Constraint, Direction, Layout, Margin, Rect},
+    style::{Color, Modifier, Style},
+    text::{Line as TextLine, Span},
+    widgets::{Block, Borders, Clear, Paragraph},
+    widgets::block::{Position, Title},
     widgets::canvas::{Canvas, Points, Line},
     Frame, Terminal,
 };
 use std::{
     collections::HashMap,
     error::Error,
-    fs::File,
-    io::{self, Write},
-    time::Duration,
+    path::Path,
+    io::{self, IsTerminal, Read},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
 };
 
 type Result<T> = std::result::Result<T, Box<dyn Error>>;
@@ -31,6 +32,62 @@ enum AppMode {
     TypstInput,
     Settings,
     PdfRender,
+    Polyline,
+    Command,
+    About,
+    Help,
+    SaveMenu,
+    Functions,
+}
+
+impl AppMode {
+    // Built-in per-mode color; App::mode_color applies user overrides
+    fn color(self) -> Color {
+        match self {
+            AppMode::Drawing => Color::Rgb(255, 105, 180), // Hot pink
+            AppMode::Selection => Color::Yellow,
+            AppMode::ColorSelection => Color::Cyan,
+            AppMode::TypstInput => Color::Green,
+            AppMode::CoordinateInput => Color::Magenta,
+            AppMode::Settings | AppMode::About | AppMode::Help => Color::Blue,
+            AppMode::PdfRender | AppMode::Command | AppMode::SaveMenu | AppMode::Functions => Color::White,
+            AppMode::Polyline => Color::LightRed,
+        }
+    }
+
+    const ALL: [AppMode; 13] = [
+        AppMode::Drawing,
+        AppMode::Selection,
+        AppMode::ColorSelection,
+        AppMode::CoordinateInput,
+        AppMode::TypstInput,
+        AppMode::Settings,
+        AppMode::PdfRender,
+        AppMode::Polyline,
+        AppMode::Command,
+        AppMode::About,
+        AppMode::Help,
+        AppMode::SaveMenu,
+        AppMode::Functions,
+    ];
+
+    fn name(self) -> &'static str {
+        match self {
+            AppMode::Drawing => "DRAW",
+            AppMode::Selection => "JUMP",
+            AppMode::ColorSelection => "COLOR",
+            AppMode::CoordinateInput => "GOTO",
+            AppMode::TypstInput => "TEXT",
+            AppMode::Settings => "SETTINGS",
+            AppMode::PdfRender => "PDF",
+            AppMode::Polyline => "POLYLINE",
+            AppMode::Command => "COMMAND",
+            AppMode::About => "ABOUT",
+            AppMode::Help => "HELP",
+            AppMode::SaveMenu => "SAVE",
+            AppMode::Functions => "FUNCTIONS",
+        }
+    }
 }
 
 #[derive(Clone, Copy, PartialEq)]
@@ -40,6 +97,133 @@ enum CoordinateSystem {
     Cylindrical,
 }
 
+#[derive(Clone, Copy, PartialEq)]
+enum AxisStyle {
+    Full,  // Solid lines across the canvas
+    Ticks, // Only short tick marks along the axes
+    Hidden,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum PaperSize {
+    A4,
+    Letter,
+}
+
+impl PaperSize {
+    fn typst_name(self) -> &'static str {
+        match self {
+            PaperSize::A4 => "a4",
+            PaperSize::Letter => "us-letter",
+        }
+    }
+
+    fn width_in(self) -> f64 {
+        match self {
+            PaperSize::A4 => 8.27,
+            PaperSize::Letter => 8.5,
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum StatusVerbosity {
+    Full,        // Key hints, brush and width
+    Minimal,     // Brush and width only
+    Coordinates, // Just the cursor position
+}
+
+// Colors of the editor itself (not of the drawing), switched as a whole
+#[derive(Clone, Copy)]
+struct Theme {
+    name: &'static str,
+    background: Color,
+    text: Color,
+    foreground: Color, // Default drawing color
+    accent: Color,    // Borders, titles and the active tab
+    axis: Color,
+    grid: Color,      // Gridlines
+    dim: Color,       // Rulers, rubber bands, previews
+    highlight: Color, // Snap targets, tooltip, calibration labels
+    cursors: &'static [(AppMode, Color)], // Per-mode cursor colors replacing the defaults
+}
+
+const THEMES: [Theme; 3] = [
+    Theme {
+        name: "dark",
+        background: Color::Black,
+        text: Color::White,
+        foreground: DEFAULT_COLOR,
+        accent: Color::Rgb(100, 149, 237),
+        axis: Color::Red,
+        grid: Color::Rgb(60, 60, 60),
+        dim: Color::DarkGray,
+        highlight: Color::Yellow,
+        cursors: &[],
+    },
+    Theme {
+        name: "light",
+        background: Color::White,
+        text: Color::Black,
+        foreground: Color::Rgb(199, 21, 133),
+        accent: Color::Rgb(38, 92, 170),
+        axis: Color::Rgb(200, 30, 30),
+        grid: Color::Rgb(215, 215, 215),
+        dim: Color::Gray,
+        highlight: Color::Rgb(190, 130, 0),
+        cursors: &[
+            (AppMode::Selection, Color::Rgb(190, 130, 0)),
+            (AppMode::ColorSelection, Color::Rgb(0, 140, 150)),
+            (AppMode::TypstInput, Color::Rgb(30, 140, 30)),
+            (AppMode::PdfRender, Color::Black),
+            (AppMode::Command, Color::Black),
+            (AppMode::SaveMenu, Color::Black),
+            (AppMode::Functions, Color::Black),
+        ],
+    },
+    Theme {
+        name: "solarized",
+        background: Color::Rgb(0, 43, 54),
+        text: Color::Rgb(131, 148, 150),
+        foreground: Color::Rgb(211, 54, 130),
+        accent: Color::Rgb(38, 139, 210),
+        axis: Color::Rgb(220, 50, 47),
+        grid: Color::Rgb(7, 54, 66),
+        dim: Color::Rgb(88, 110, 117),
+        highlight: Color::Rgb(181, 137, 0),
+        cursors: &[
+            (AppMode::Drawing, Color::Rgb(211, 54, 130)),
+            (AppMode::Selection, Color::Rgb(181, 137, 0)),
+            (AppMode::ColorSelection, Color::Rgb(42, 161, 152)),
+            (AppMode::TypstInput, Color::Rgb(133, 153, 0)),
+            (AppMode::CoordinateInput, Color::Rgb(108, 113, 196)),
+            (AppMode::Settings, Color::Rgb(38, 139, 210)),
+            (AppMode::About, Color::Rgb(38, 139, 210)),
+            (AppMode::Help, Color::Rgb(38, 139, 210)),
+            (AppMode::Polyline, Color::Rgb(203, 75, 22)),
+            (AppMode::PdfRender, Color::Rgb(147, 161, 161)),
+            (AppMode::Command, Color::Rgb(147, 161, 161)),
+            (AppMode::SaveMenu, Color::Rgb(147, 161, 161)),
+            (AppMode::Functions, Color::Rgb(147, 161, 161)),
+        ],
+    },
+];
+
+impl Theme {
+    fn named(name: &str) -> Option<Theme> {
+        THEMES.into_iter().find(|theme| theme.name.eq_ignore_ascii_case(name.trim()))
+    }
+}
+
+// What continuous-draw strokes are drawn with
+#[derive(Clone, PartialEq)]
+enum StrokeBrush {
+    Brush,           // The current brush, same as single stamps
+    Auto,            // The point brush becomes - | / \ following each segment
+    Direction,       // Every line brush follows the stroke's local slope
+    Fixed(DrawChar), // Always this glyph, whatever the stamp brush is
+}
+
 #[derive(Clone, PartialEq)]
 enum DrawChar {
     Point,
@@ -51,9 +235,387 @@ enum DrawChar {
     Text(char),  // Any ASCII character
 }
 
+impl DrawChar {
+    fn glyph(&self) -> char {
+        match self {
+            DrawChar::Point => '•',
+            DrawChar::Horizontal => '-',
+            DrawChar::Vertical => '|',
+            DrawChar::Cross => '+',
+            DrawChar::DiagRight => '/',
+            DrawChar::DiagLeft => '\\',
+            DrawChar::Text(ch) => *ch,
+        }
+    }
+
+    fn from_glyph(ch: char) -> Option<DrawChar> {
+        match ch {
+            ' ' => None,
+            '•' => Some(DrawChar::Point),
+            '-' => Some(DrawChar::Horizontal),
+            '|' => Some(DrawChar::Vertical),
+            '+' => Some(DrawChar::Cross),
+            '/' => Some(DrawChar::DiagRight),
+            '\\' => Some(DrawChar::DiagLeft),
+            _ => Some(DrawChar::Text(ch)),
+        }
+    }
+
+    // Next/previous brush in BRUSHES order; text brushes re-enter at either end
+    fn cycle(&self, forward: bool) -> DrawChar {
+        let count = BRUSHES.len();
+        let next = match BRUSHES.iter().position(|brush| brush == self) {
+            Some(i) if forward => (i + 1) % count,
+            Some(i) => (i + count - 1) % count,
+            None if forward => 0,
+            None => count - 1,
+        };
+        BRUSHES[next].clone()
+    }
+
+    fn name(&self) -> String {
+        match self {
+            DrawChar::Point => "point".to_string(),
+            DrawChar::Horizontal => "horizontal".to_string(),
+            DrawChar::Vertical => "vertical".to_string(),
+            DrawChar::Cross => "cross".to_string(),
+            DrawChar::DiagRight => "diag-right".to_string(),
+            DrawChar::DiagLeft => "diag-left".to_string(),
+            DrawChar::Text(ch) => format!("text({})", ch),
+        }
+    }
+
+    // Accepts a brush name ("cross", "diag-left", ...) or a single glyph
+    fn from_name(name: &str) -> Option<DrawChar> {
+        let name = name.trim();
+        match name.to_lowercase().as_str() {
+            "point" => return Some(DrawChar::Point),
+            "horizontal" => return Some(DrawChar::Horizontal),
+            "vertical" => return Some(DrawChar::Vertical),
+            "cross" => return Some(DrawChar::Cross),
+            "diag-right" => return Some(DrawChar::DiagRight),
+            "diag-left" => return Some(DrawChar::DiagLeft),
+            _ => {}
+        }
+        let inner = name.strip_prefix("text(").and_then(|rest| rest.strip_suffix(')')).unwrap_or(name);
+        let mut chars = inner.chars();
+        match (chars.next(), chars.next()) {
+            (Some(ch), None) if !ch.is_control() => DrawChar::from_glyph(ch),
+            _ => None,
+        }
+    }
+}
+
+// Plotting colors cycled with C, starting from the default pink
+const PALETTE: [(&str, Color); 8] = [
+    ("pink", Color::Rgb(255, 105, 180)),
+    ("blue", Color::Rgb(31, 119, 180)),
+    ("orange", Color::Rgb(255, 127, 14)),
+    ("green", Color::Rgb(44, 160, 44)),
+    ("red", Color::Rgb(214, 39, 40)),
+    ("purple", Color::Rgb(148, 103, 189)),
+    ("brown", Color::Rgb(140, 86, 75)),
+    ("gray", Color::Rgb(127, 127, 127)),
+];
+
+// Marker shapes for the point brush, cycled with M
+const POINT_MARKERS: [char; 6] = ['•', '·', '○', '●', '×', '*'];
+
+// Export placeholders for empty cells, cycled with B in settings
+const EMPTY_CELL_CHARS: [char; 3] = [' ', '.', '·'];
+
+// Brushes cycled with Tab / Shift+Tab
+const BRUSHES: [DrawChar; 6] = [
+    DrawChar::Point,
+    DrawChar::Horizontal,
+    DrawChar::Vertical,
+    DrawChar::Cross,
+    DrawChar::DiagRight,
+    DrawChar::DiagLeft,
+];
+
+#[derive(Clone, Copy, PartialEq, Default)]
+struct TextAttrs {
+    bold: bool,
+    italic: bool,
+    underline: bool,
+}
+
+impl TextAttrs {
+    fn modifier(&self) -> Modifier {
+        let mut modifier = Modifier::empty();
+        if self.bold {
+            modifier |= Modifier::BOLD;
+        }
+        if self.italic {
+            modifier |= Modifier::ITALIC;
+        }
+        if self.underline {
+            modifier |= Modifier::UNDERLINED;
+        }
+        modifier
+    }
+
+    // Wrap text in the matching typst emphasis markup
+    fn wrap_typst(&self, text: &str) -> String {
+        let mut out = text.to_string();
+        if self.underline {
+            out = format!("#underline[{}]", out);
+        }
+        if self.italic {
+            out = format!("_{}_", out);
+        }
+        if self.bold {
+            out = format!("*{}*", out);
+        }
+        out
+    }
+
+    fn label(&self) -> String {
+        let mut flags = Vec::new();
+        if self.bold {
+            flags.push("bold");
+        }
+        if self.italic {
+            flags.push("italic");
+        }
+        if self.underline {
+            flags.push("underline");
+        }
+        if flags.is_empty() {
+            "plain".to_string()
+        } else {
+            flags.join("+")
+        }
+    }
+}
+
+#[derive(Clone, PartialEq)]
+struct CanvasCell {
+    ch: DrawChar,
+    color: Color,
+    attrs: TextAttrs,
+}
+
+// A line of text typed in TypstInput, kept for the typst export
+#[derive(Clone)]
+struct TypstLine {
+    text: String,
+    attrs: TextAttrs,
+}
+
+// Characters auto-closed in typst input until changed with :pairs
+const DEFAULT_PAIRS: [(char, char); 6] = [('(', ')'), ('[', ']'), ('{', '}'), ('$', '$'), ('"', '"'), ('\'', '\'')];
+
+// Index into HEAT_GLYPHS for a cell hit `count` times when the busiest has
+// `max`; the busiest cell is always the densest glyph
+fn heat_level(count: u32, max: u32) -> usize {
+    (count as usize * HEAT_GLYPHS.len()).div_ceil(max.max(1) as usize).clamp(1, HEAT_GLYPHS.len()) - 1
+}
+
+// Count range shaded by each heatmap glyph, skipping glyphs no count maps to
+fn heat_legend(max: u32) -> Vec<(char, u32, u32)> {
+    let levels = HEAT_GLYPHS.len() as u32;
+    (0..levels)
+        .filter_map(|level| {
+            let low = level * max / levels + 1;
+            let high = (level + 1) * max / levels;
+            (low <= high).then_some((HEAT_GLYPHS[level as usize], low, high))
+        })
+        .collect()
+}
+
+// Typed lines that look like a single equation (one `=` and an arithmetic
+// operator) become inline math; lines already using `$` are left alone
+fn typst_markup(line: &str) -> String {
+    let is_equation = line.matches('=').count() == 1 && line.contains(['+', '-', '*', '/']);
+    if is_equation && !line.contains('$') {
+        format!("${}$", line)
+    } else {
+        line.to_string()
+    }
+}
+
+// Hot pink, also the text fill of the exported typst page
+const DEFAULT_COLOR: Color = Color::Rgb(255, 105, 180);
+
+// A curve kept by :fn and drawn over the canvas until baked in with the
+// manager's b
+#[derive(Clone)]
+struct PlottedFunction {
+    source: String,
+    expr: Expr,
+    polar: bool, // r = f(theta) rather than y = f(x)
+    color: Color,
+    brush: DrawChar, // Glyph used when baked into the canvas
+}
+
+impl PlottedFunction {
+    fn label(&self) -> String {
+        format!("{} = {}", if self.polar { "r" } else { "y" }, self.source)
+    }
+}
+
+// State restored by one undo step; typed text is kept alongside the canvas
+// so undoing `c` or a text entry also restores what the export contains,
+// and heatmap counts so the density legend matches the restored glyphs
+struct UndoSnapshot {
+    canvas: Vec<Vec<Option<CanvasCell>>>,
+    typst_content: Vec<TypstLine>,
+    heat_counts: HashMap<(usize, usize), u32>,
+}
+
+// Canvas snapshots shown one after another by :replay
+struct Replay {
+    frames: Vec<Vec<Vec<Option<CanvasCell>>>>,
+    next_frame: usize,
+    delay_ms: u128,
+    last_step: Instant,
+    final_canvas: Vec<Vec<Option<CanvasCell>>>,
+}
+
+// Cells over which a continuous stroke sweeps from one gradient color to the other
+const GRADIENT_PERIOD: usize = 16;
+
+// Default delay between :replay frames
+const REPLAY_DELAY_MS: u128 = 300;
+
+// Maximum number of canvas snapshots kept for undo
+const UNDO_LIMIT: usize = 50;
+
+const HELP_TEXT: &str = "\
+Drawing mode
+  h j k l      move cursor (Alt: quarter cell)   J / K    scroll
+  space        stamp brush                        u        undo
+  . - | + / \\  select brush                       d        continuous draw
+  Tab / S-Tab  cycle brushes                       D / X    clear row / column
+  Y            copy row onto the row below        Q / @    record / replay macro
+  M            cycle point marker (• · ○ ● × *)   W        set window xmin,xmax,ymin,ymax
+  f            jump via keyboard grid            g        go to coordinate
+  o            set origin                         1 2 3    coordinate system
+  0            jump to origin                     z        center on drawing
+  m            label corners and origin
+  arrows       move (Shift: 5 cells, no drawing)  C-s / C-z  save / undo
+  a            toggle axes                        n        toggle grid snap
+  p            polyline tool                      y        plot y = f(x)
+  i            typst text input                   x        color (hex)
+  s            save typst                         r        open PDF
+  S            save as (typst / tikz)           C        cycle palette color
+  :            command mode                       c        clear canvas
+  ?            settings                           q        quit
+
+Commands
+  :w [name]  :wq  :q  :e [file] (open a saved drawing with its stamps)
+  :plot f(x)  :shade a b [char]  :implicit f = g
+  :plot f(theta) in polar mode draws r = f(theta), e.g. 1 + cos(theta)
+  :fn f(x) (live curve that follows origin and scale)  :fns (manage them)
+  :tabnew [name]  :tabclose  :tabn  :tabp  ([ and ] switch tabs)
+  :ray deg  :xline deg (line through cursor at an angle)
+  :rotate deg (turn the whole drawing about the origin)
+  :polygon sides radius [fill] (regular polygon centred on the cursor, max 360 sides)
+  :note text (label pinned to the cursor's data point)  :notes [clear]
+  :field dx ; dy [; spacing] (arrows of a vector field in x and y)
+  :pairs [()[]...] (show or set the auto-pair table of typst input)
+  :precision 0-6|auto (decimals in coordinate readouts)
+  :strokebrush auto|direction|brush|name (continuous-draw glyph)
+  :minimap (toggle the canvas overview; click it to jump there)
+  :preview (toggle the typst source that :w would save, beside the canvas)
+  :window xmin,xmax,ymin,ymax (fit that data window to the canvas)
+  :calibrate x,y (twice, at two known points, to trace over a plot)
+  :page a4|letter|off (shrink exported text to fit the page width)
+  :aspect ratio (terminal cell width:height used by exports, default 0.5)
+  :gridoffset x y (shift ticks and the data grid away from the origin)
+  :grow [rows] (add rows to the bottom of the canvas, default 100, max 5000)
+  :smooth (redraw the last continuous-draw stroke as a smooth curve)
+  :replay [ms] (animate the undo history, Esc stops)
+  :caption text  :stamp name w h  :place name  :stamps  :import file.txt
+  :table rows cols [width [height]] (ruled grid at the cursor to fill in)
+  :export w h file.typ|.tex|.txt (save only the w x h region at the cursor)
+  :macro [count] (replay the Q-recorded keys, e.g. a tick and a move, N times)
+  :heatmap file.csv (x,y points shaded by how many share a cell)  :heatmap clear
+
+Press ? for settings or Esc to return to drawing";
+
+// Most rows :grow can reach; every tab and undo snapshot holds this many
+const MAX_CANVAS_ROWS: usize = 5000;
+
+// Range of units per cell and data grid spacing reachable with < > [ ];
+// halving toward zero would leave the grid and tick loops unable to advance
+const MIN_SCALE: f64 = 1e-6;
+const MAX_SCALE: f64 = 1e6;
+
+// Cursor movement in cells for Alt+hjkl fine nudging
+const FINE_STEP: f64 = 0.25;
+
+// Cells moved by Shift+arrow keys in drawing mode
+const SHIFT_MOVE_STEP: f64 = 5.0;
+
+// Cells (in each direction) within which polyline vertices snap to line ends
+const ENDPOINT_SNAP_RADIUS: i32 = 1;
+
+// Most sides :polygon draws; more is indistinguishable from a circle
+const MAX_POLYGON_SIDES: usize = 360;
+
+// Most replays of a macro one :macro command may ask for
+const MACRO_REPEAT_LIMIT: usize = 1000;
+
+// Heatmap shades from sparse to dense; cells split the counts evenly
+const HEAT_GLYPHS: [char; 4] = ['░', '▒', '▓', '█'];
+
+// Inner width of the minimap beside the canvas
+const MINIMAP_WIDTH: u16 = 20;
+
+// Share of the screen width given to the typst preview panel
+const TYPST_PREVIEW_PERCENT: u16 = 40;
+
+// Columns taken by the row-number ruler, including a gap before the canvas
+const RULER_WIDTH: u16 = 4;
+
+// How long the cursor flashes after grid snapping moved it
+const SNAP_FLASH_MS: u128 = 200;
+
+// Duration of each cursor blink phase, independent of the frame rate
+const BLINK_INTERVAL_MS: u128 = 500;
+
+// Per-tab drawing state. The active tab's state lives directly on `App`;
+// the other tabs are parked here and swapped in when switching.
+struct Tab {
+    canvas: Vec<Vec<Option<CanvasCell>>>,
+    cursor_x: f64,
+    cursor_y: f64,
+    origin_x: f64,
+    origin_y: f64,
+    scroll_y: usize,
+    typst_content: Vec<TypstLine>,
+    undo_stack: Vec<UndoSnapshot>,
+    save_path: String,
+    annotations: Vec<(f64, f64, String)>,
+    heat_counts: HashMap<(usize, usize), u32>,
+    functions: Vec<PlottedFunction>,
+}
+
+impl Tab {
+    fn new(canvas_width: usize, virtual_height: usize, save_path: String) -> Tab {
+        Tab {
+            canvas: vec![vec![None; canvas_width]; virtual_height],
+            cursor_x: 40.0,
+            cursor_y: 20.0,
+            origin_x: 40.0,
+            origin_y: 20.0,
+            scroll_y: 0,
+            typst_content: Vec::new(),
+            undo_stack: Vec::new(),
+            save_path,
+            annotations: Vec::new(),
+            heat_counts: HashMap::new(),
+            functions: Vec::new(),
+        }
+    }
+}
+
 struct App {
     mode: AppMode,
-    canvas: Vec<Vec<Option<DrawChar>>>,
+    canvas: Vec<Vec<Option<CanvasCell>>>,
     cursor_x: f64,
     cursor_y: f64,
     canvas_width: usize,
@@ -73,9 +635,88 @@ struct App {
     coordinate_input: String,
     origin_x: f64,
     origin_y: f64,
+    scale_x: f64, // Data units per cell
+    scale_y: f64,
     grid_snap: bool,
+    data_snap: bool,
+    data_snap_step: f64,
     text_buffer: String,
-    typst_content: Vec<String>,
+    pending_closers: String, // Auto-paired closers after the text cursor, innermost first
+    auto_pair: bool,
+    pairs: Vec<(char, char)>, // Opener/closer table for auto-pairing
+    typst_content: Vec<TypstLine>,
+    text_attrs: TextAttrs,
+    blink_started: Instant,
+    blink_cursor: bool,
+    origin_snap: bool,
+    status_message: Option<String>,
+    polyline: Vec<(f64, f64)>,
+    canvas_version: u64, // Bumped on every canvas edit to invalidate the render cache
+    render_cache: Vec<Vec<(usize, Span<'static>)>>,
+    render_cache_key: Option<(u64, usize)>,
+    dirty_rows: Option<(usize, usize)>, // Rows touched by cell writes since the last cache refresh
+    dirty_writes: u64, // canvas_version bumps accounted for by dirty_rows
+    command_input: String,
+    save_path: String,
+    show_coordinates: bool,
+    undo_stack: Vec<UndoSnapshot>,
+    last_plot: Option<Expr>,
+    show_tooltip: bool,
+    show_calibration: bool, // Label the visible corners and the origin with their coordinates
+    line_width: usize, // 1-3 cells
+    stamps: HashMap<String, Vec<Vec<Option<CanvasCell>>>>,
+    mode_colors: HashMap<&'static str, Color>, // DRAVI_CURSOR_COLORS overrides, keyed by mode name
+    theme: Theme,
+    annotations: Vec<(f64, f64, String)>, // Labels pinned to data coordinates, not cells
+    heat_counts: HashMap<(usize, usize), u32>, // :heatmap points landing in each cell
+    functions: Vec<PlottedFunction>, // Live curves from :fn, redrawn every frame
+    function_selected: usize, // Highlighted row of the :fns manager
+    function_editing: Option<usize>, // Entry the next :fn replaces
+    macro_recording: Option<Vec<KeyEvent>>, // Keys typed since Q started recording
+    macro_keys: Vec<KeyEvent>, // Last recorded macro, replayed by @
+    macro_running: bool,
+    calibration_mark: Option<(f64, f64, f64, f64)>, // First :calibrate point: cell x, y and its data x, y
+    pretty_math: bool,
+    typst_available: bool,
+    axis_style: AxisStyle,
+    merge_draw: bool, // Drawing skips occupied cells instead of overwriting
+    axis_snap: bool,
+    figure_export: bool, // Save as a standalone #figure instead of a full page
+    exact_grid: bool, // Export rows padded to the drawing's width instead of trimmed
+    auto_math: bool, // Export typed equations as inline math without explicit `$`
+    empty_cell: char, // Stands in for empty cells in exported drawings
+    snap_preview: bool, // Faint marks where hjkl would land while snapping
+    figure_caption: String,
+    import_wrap: bool, // Wrap long lines on :import instead of clipping
+    snap_flash: Option<Instant>,
+    tabs: Vec<Tab>, // The slot at `active_tab` is stale while that tab is active
+    active_tab: usize,
+    status_verbosity: StatusVerbosity,
+    replay: Option<Replay>,
+    grid_offset_x: f64, // Data-grid and tick phase in data units, independent of the origin
+    grid_offset_y: f64,
+    pdf_viewer: Option<std::process::Child>, // Terminal running tdf, if we spawned one
+    pdf_compile: Option<(std::process::Child, String)>, // Background typst compile and its file
+    compile_queued: Vec<String>, // Files saved while a compile was running, each once
+    spawn_pdf_viewer: bool, // Off for users who keep their own viewer open
+    center_cursor: bool, // Scroll so the cursor stays centered instead of scrolling at the edges
+    show_gridlines: bool, // Dashed lines across the canvas at every major tick
+    show_rulers: bool, // Raw column/row indices along the top and left edges
+    show_minimap: bool, // Thumbnail of the whole scrollable canvas beside it
+    show_typst_preview: bool, // Live typst source of the drawing beside the canvas
+    y_down: bool, // Data y grows down the screen, as in image coordinates
+    point_marker: char,
+    stroke_brush: StrokeBrush,
+    coord_precision: Option<usize>, // Decimals in coordinate readouts; None picks per position
+    fit_page: Option<PaperSize>, // Scale exported text to fit this paper's width
+    angle_snap: bool, // Polyline segments restricted to multiples of 45°
+    endpoint_snap: bool, // Polyline vertices pulled onto nearby line ends
+    gradient: Option<(Color, Color)>, // Start/end colors for line tools
+    cell_aspect: f64, // Terminal cell width:height, undone by exports so shapes aren't squashed
+    smooth_strokes: bool, // Smooth each continuous-draw stroke when it ends
+    stroke_path: Vec<(f64, f64)>, // Cursor path of the current/last continuous-draw stroke
+    stroke_base: Option<Vec<Vec<Option<CanvasCell>>>>, // Canvas before that stroke
+    stroke_version: u64, // canvas_version right after the stroke was last extended
 }
 
 impl App {
@@ -111,7 +752,7 @@ impl App {
             should_quit: false,
             keyboard_grid,
             current_char: DrawChar::Point,
-            current_color: Color::Rgb(255, 105, 180), // Hot pink
+            current_color: DEFAULT_COLOR,
             color_input: String::new(),
             continuous_draw: false,
             last_cursor_x: 40.0,
@@ -121,13 +762,213 @@ impl App {
             coordinate_input: String::new(),
             origin_x: 40.0,
             origin_y: 20.0,
+            scale_x: 1.0,
+            scale_y: 1.0,
             grid_snap: false,
+            data_snap: false,
+            data_snap_step: 1.0,
             text_buffer: String::new(),
+            pending_closers: String::new(),
+            auto_pair: true,
+            pairs: DEFAULT_PAIRS.to_vec(),
             typst_content: Vec::new(),
+            text_attrs: TextAttrs::default(),
+            blink_started: Instant::now(),
+            blink_cursor: true,
+            origin_snap: false,
+            status_message: None,
+            polyline: Vec::new(),
+            canvas_version: 0,
+            render_cache: Vec::new(),
+            render_cache_key: None,
+            dirty_rows: None,
+            dirty_writes: 0,
+            command_input: String::new(),
+            save_path: "drawing.typ".to_string(),
+            show_coordinates: true,
+            undo_stack: Vec::new(),
+            last_plot: None,
+            show_tooltip: false,
+            show_calibration: false,
+            line_width: 1,
+            stamps: HashMap::new(),
+            mode_colors: HashMap::new(),
+            theme: THEMES[0],
+            annotations: Vec::new(),
+            heat_counts: HashMap::new(),
+            functions: Vec::new(),
+            function_selected: 0,
+            function_editing: None,
+            macro_recording: None,
+            macro_keys: Vec::new(),
+            macro_running: false,
+            calibration_mark: None,
+            pretty_math: true,
+            typst_available: typst_on_path(),
+            axis_style: AxisStyle::Full,
+            merge_draw: false,
+            axis_snap: false,
+            figure_export: false,
+            exact_grid: false,
+            auto_math: true,
+            empty_cell: ' ',
+            snap_preview: false,
+            figure_caption: String::new(),
+            import_wrap: false,
+            snap_flash: None,
+            tabs: vec![Tab::new(0, 0, String::new())],
+            active_tab: 0,
+            status_verbosity: StatusVerbosity::Full,
+            replay: None,
+            grid_offset_x: 0.0,
+            grid_offset_y: 0.0,
+            pdf_viewer: None,
+            pdf_compile: None,
+            compile_queued: Vec::new(),
+            spawn_pdf_viewer: true,
+            center_cursor: false,
+            show_gridlines: false,
+            show_rulers: false,
+            show_minimap: false,
+            show_typst_preview: false,
+            y_down: false,
+            point_marker: '•',
+            stroke_brush: StrokeBrush::Auto,
+            coord_precision: None,
+            fit_page: None,
+            angle_snap: false,
+            endpoint_snap: true,
+            gradient: None,
+            cell_aspect: 0.5,
+            smooth_strokes: false,
+            stroke_path: Vec::new(),
+            stroke_base: None,
+            stroke_version: 0,
+        }
+    }
+
+    // App::new() with the DRAVI_* environment applied. Kept apart so tests
+    // and anything else built on App::new() don't depend on the user's setup.
+    fn from_env() -> App {
+        let mut app = App::new();
+        app.apply_env();
+        app
+    }
+
+    // Startup defaults from DRAVI_* environment variables; invalid values are ignored
+    fn apply_env(&mut self) {
+        use std::env;
+
+        if let Ok(hex) = env::var("DRAVI_DEFAULT_COLOR") {
+            if let Some(color) = self.parse_hex_color(hex.trim_start_matches('#')) {
+                self.current_color = color;
+            }
+        }
+        if let Ok(system) = env::var("DRAVI_COORD_SYSTEM") {
+            match system.to_lowercase().as_str() {
+                "cartesian" => self.coordinate_system = CoordinateSystem::Cartesian,
+                "polar" => self.coordinate_system = CoordinateSystem::Polar,
+                "cylindrical" => self.coordinate_system = CoordinateSystem::Cylindrical,
+                _ => {}
+            }
+        }
+        if let Some(axes) = env::var("DRAVI_AXES").ok().and_then(|v| parse_flag(&v)) {
+            self.show_axes = axes;
+        }
+        if let Some(snap) = env::var("DRAVI_GRID_SNAP").ok().and_then(|v| parse_flag(&v)) {
+            self.grid_snap = snap;
+        }
+        if let Ok(name) = env::var("DRAVI_BRUSH") {
+            self.set_brush_from_config(&name);
+        }
+        if let Ok(Some(precision)) = env::var("DRAVI_COORD_PRECISION").map(|v| parse_precision(&v)) {
+            self.coord_precision = precision;
+        }
+        if let Ok(name) = env::var("DRAVI_THEME") {
+            self.set_theme_from_config(&name);
+        }
+        // e.g. DRAVI_CURSOR_COLORS="draw=00ff00,jump=ffffff"
+        if let Ok(spec) = env::var("DRAVI_CURSOR_COLORS") {
+            for entry in spec.split(',') {
+                let Some((name, hex)) = entry.split_once('=') else { continue };
+                let mode = AppMode::ALL.into_iter().find(|mode| mode.name().eq_ignore_ascii_case(name.trim()));
+                let color = self.parse_hex_color(hex.trim().trim_start_matches('#'));
+                if let (Some(mode), Some(color)) = (mode, color) {
+                    self.mode_colors.insert(mode.name(), color);
+                }
+            }
+        }
+    }
+
+    // Per-mode color shared by the cursor and the mode badge: the user's
+    // override, else the theme's, else the built-in one
+    fn mode_color(&self, mode: AppMode) -> Color {
+        self.mode_colors.get(mode.name()).copied()
+            .or_else(|| self.theme.cursors.iter().find(|(m, _)| *m == mode).map(|&(_, color)| color))
+            .unwrap_or_else(|| mode.color())
+    }
+
+    fn set_theme_from_config(&mut self, name: &str) {
+        match Theme::named(name) {
+            Some(theme) => self.set_theme(theme),
+            None => self.status_message = Some(format!("Unknown theme '{}', using {}", name.trim(), self.theme.name)),
+        }
+    }
+
+    // The drawing color follows the theme unless one was picked explicitly
+    fn set_theme(&mut self, theme: Theme) {
+        if self.current_color == self.theme.foreground {
+            self.current_color = theme.foreground;
+        }
+        self.theme = theme;
+    }
+
+    // Unrecognized brush names fall back to the point brush
+    fn set_brush_from_config(&mut self, name: &str) {
+        self.current_char = DrawChar::from_name(name).unwrap_or_else(|| {
+            self.status_message = Some(format!("Unknown brush '{}', using point", name.trim()));
+            DrawChar::Point
+        });
+    }
+
+    // Brush saved by remember_brush at the end of the last session
+    fn restore_brush(&mut self) {
+        if let Some(name) = brush_state_path().and_then(|path| std::fs::read_to_string(path).ok()) {
+            self.set_brush_from_config(&name);
+        }
+    }
+
+    fn remember_brush(&self) {
+        if let Some(path) = brush_state_path() {
+            if let Some(dir) = path.parent() {
+                let _ = std::fs::create_dir_all(dir);
+            }
+            let _ = std::fs::write(path, self.current_char.name());
         }
     }
 
     fn handle_key(&mut self, key: KeyEvent) {
+        // Terminals that report key releases would otherwise act twice per
+        // press; repeats from a held key count as presses
+        if key.kind == KeyEventKind::Release {
+            return;
+        }
+        // Restart the blink cycle so the cursor is visible right after input
+        self.blink_started = Instant::now();
+        self.status_message = None;
+        if self.replay.is_some() {
+            // Input is ignored while replaying, except for Esc to stop early
+            if key.code == KeyCode::Esc {
+                self.stop_replay();
+            }
+            return;
+        }
+        // Q in drawing mode ends the recording rather than being part of it
+        // and keys replayed by @ are recorded as the @ alone
+        let stops_recording = self.mode == AppMode::Drawing && key.code == KeyCode::Char('Q');
+        if let Some(keys) = self.macro_recording.as_mut().filter(|_| !stops_recording && !self.macro_running) {
+            keys.push(key);
+        }
         match self.mode {
             AppMode::Drawing => self.handle_drawing_keys(key),
             AppMode::Selection => self.handle_selection_keys(key),
@@ -136,28 +977,108 @@ impl App {
             AppMode::TypstInput => self.handle_typst_input_keys(key),
             AppMode::Settings => self.handle_settings_keys(key),
             AppMode::PdfRender => self.handle_pdf_render_keys(key),
+            AppMode::Polyline => self.handle_polyline_keys(key),
+            AppMode::Command => self.handle_command_keys(key),
+            AppMode::About => self.handle_info_screen_keys(key),
+            AppMode::Help => self.handle_info_screen_keys(key),
+            AppMode::SaveMenu => self.handle_save_menu_keys(key),
+            AppMode::Functions => self.handle_functions_keys(key),
         }
     }
 
+    fn snap_flash_active(&self) -> bool {
+        self.snap_flash.is_some_and(|start| start.elapsed().as_millis() < SNAP_FLASH_MS)
+    }
+
+    fn cursor_visible(&self) -> bool {
+        !self.blink_cursor || (self.blink_started.elapsed().as_millis() / BLINK_INTERVAL_MS).is_multiple_of(2)
+    }
+
     fn handle_drawing_keys(&mut self, key: KeyEvent) {
+        // Alt+hjkl nudges the cursor by a quarter cell
+        if key.modifiers.contains(KeyModifiers::ALT) {
+            match key.code {
+                KeyCode::Char('h') => self.draw_move(-FINE_STEP, 0.0),
+                KeyCode::Char('j') => self.draw_move(0.0, FINE_STEP),
+                KeyCode::Char('k') => self.draw_move(0.0, -FINE_STEP),
+                KeyCode::Char('l') => self.draw_move(FINE_STEP, 0.0),
+                _ => {}
+            }
+            return;
+        }
+
+        // Ctrl bindings; unbound Ctrl combinations do nothing rather than
+        // falling through to the plain key (Ctrl+c must not clear the canvas)
+        if key.modifiers.contains(KeyModifiers::CONTROL) {
+            match key.code {
+                KeyCode::Char('s') => self.save_typst(),
+                KeyCode::Char('z') => self.undo(),
+                _ => {}
+            }
+            return;
+        }
+
         match key.code {
             KeyCode::Char('q') => self.should_quit = true,
-            KeyCode::Char('h') => self.move_cursor(-1.0, 0.0),
-            KeyCode::Char('j') => self.move_cursor(0.0, 1.0),
-            KeyCode::Char('k') => self.move_cursor(0.0, -1.0),
-            KeyCode::Char('l') => self.move_cursor(1.0, 0.0),
+            // Shift+arrows move several cells at once without drawing
+            KeyCode::Left if key.modifiers.contains(KeyModifiers::SHIFT) => self.move_cursor(-SHIFT_MOVE_STEP, 0.0),
+            KeyCode::Down if key.modifiers.contains(KeyModifiers::SHIFT) => self.move_cursor(0.0, SHIFT_MOVE_STEP),
+            KeyCode::Up if key.modifiers.contains(KeyModifiers::SHIFT) => self.move_cursor(0.0, -SHIFT_MOVE_STEP),
+            KeyCode::Right if key.modifiers.contains(KeyModifiers::SHIFT) => self.move_cursor(SHIFT_MOVE_STEP, 0.0),
+            KeyCode::Char('h') | KeyCode::Left => self.draw_move(-1.0, 0.0),
+            KeyCode::Char('j') | KeyCode::Down => self.draw_move(0.0, 1.0),
+            KeyCode::Char('k') | KeyCode::Up => self.draw_move(0.0, -1.0),
+            KeyCode::Char('l') | KeyCode::Right => self.draw_move(1.0, 0.0),
             KeyCode::Char('f') => self.mode = AppMode::Selection,
-            KeyCode::Char(' ') => self.draw_char(),
+            KeyCode::Char(' ') => {
+                self.push_undo();
+                self.draw_char();
+            }
             KeyCode::Char('?') => self.mode = AppMode::Settings,
-            KeyCode::Char('c') => self.clear_canvas(),
+            KeyCode::Char('c') => {
+                self.push_undo();
+                self.clear_canvas();
+            }
+            KeyCode::Char('u') => self.undo(),
             KeyCode::Char('s') => self.save_typst(),
+            KeyCode::Char('S') => self.mode = AppMode::SaveMenu,
             KeyCode::Char('x') => self.mode = AppMode::ColorSelection,
-            KeyCode::Char('d') => self.continuous_draw = !self.continuous_draw,
-            KeyCode::Char('a') => self.show_axes = !self.show_axes,
+            KeyCode::Char('C') => {
+                // A color off the palette restarts the cycle at its first entry
+                let next = PALETTE.iter().position(|&(_, c)| c == self.current_color).map_or(0, |i| (i + 1) % PALETTE.len());
+                let (name, color) = PALETTE[next];
+                self.current_color = color;
+                self.gradient = None;
+                self.status_message = Some(format!("Color: {} ({})", name, color_hex(color)));
+            }
+            KeyCode::Char('d') => self.toggle_continuous_draw(),
+            KeyCode::Char('a') => self.toggle_axes(),
             KeyCode::Char('g') => self.mode = AppMode::CoordinateInput,
             KeyCode::Char('i') => self.mode = AppMode::TypstInput,
-            KeyCode::Char('n') => self.grid_snap = !self.grid_snap,
+            KeyCode::Char(':') => self.mode = AppMode::Command,
+            KeyCode::Char('y') => {
+                self.command_input = "plot ".to_string();
+                self.mode = AppMode::Command;
+            }
+            KeyCode::Char('W') => {
+                self.command_input = "window ".to_string();
+                self.mode = AppMode::Command;
+            }
+            KeyCode::Char('n') => self.toggle_grid_snap(),
+            KeyCode::Char(']') => self.switch_tab((self.active_tab + 1) % self.tabs.len()),
+            KeyCode::Char('[') => self.switch_tab((self.active_tab + self.tabs.len() - 1) % self.tabs.len()),
+            KeyCode::Char('p') => {
+                self.polyline.clear();
+                self.mode = AppMode::Polyline;
+            }
             // Character selection
+            KeyCode::Char('M') => {
+                let next = POINT_MARKERS.iter().position(|&m| m == self.point_marker).map_or(0, |i| (i + 1) % POINT_MARKERS.len());
+                self.point_marker = POINT_MARKERS[next];
+                self.current_char = DrawChar::Point;
+            }
+            KeyCode::Tab => self.current_char = self.current_char.cycle(true),
+            KeyCode::BackTab => self.current_char = self.current_char.cycle(false),
             KeyCode::Char('.') => self.current_char = DrawChar::Point,
             KeyCode::Char('-') => self.current_char = DrawChar::Horizontal,
             KeyCode::Char('|') => self.current_char = DrawChar::Vertical,
@@ -165,21 +1086,31 @@ impl App {
             KeyCode::Char('/') => self.current_char = DrawChar::DiagRight,
             KeyCode::Char('\\') => self.current_char = DrawChar::DiagLeft,
             // Coordinate system switching
-            KeyCode::Char('1') => self.coordinate_system = CoordinateSystem::Cartesian,
-            KeyCode::Char('2') => self.coordinate_system = CoordinateSystem::Polar,
-            KeyCode::Char('3') => self.coordinate_system = CoordinateSystem::Cylindrical,
+            KeyCode::Char('1') => self.set_coordinate_system(CoordinateSystem::Cartesian),
+            KeyCode::Char('2') => self.set_coordinate_system(CoordinateSystem::Polar),
+            KeyCode::Char('3') => self.set_coordinate_system(CoordinateSystem::Cylindrical),
             // Origin setting
-            KeyCode::Char('o') => {
-                self.origin_x = self.cursor_x;
-                self.origin_y = self.cursor_y;
-            }
+            KeyCode::Char('o') => self.set_origin_at_cursor(),
+            KeyCode::Char('m') => self.show_calibration = !self.show_calibration,
+            // Quick navigation
+            KeyCode::Char('0') => self.jump_to(self.origin_x, self.origin_y),
+            KeyCode::Char('z') => self.jump_to_content_center(),
             // Scrolling
             KeyCode::Char('J') => self.scroll_down(),
             KeyCode::Char('K') => self.scroll_up(),
+            // Clearing or duplicating the row / column under the cursor
+            KeyCode::Char('D') => self.clear_row(),
+            KeyCode::Char('X') => self.clear_column(),
+            KeyCode::Char('Y') => self.duplicate_row(),
+            // Macros
+            KeyCode::Char('Q') => self.toggle_macro_recording(),
+            KeyCode::Char('@') => self.play_macro(1),
             KeyCode::Char('r') => {
-                self.open_pdf();
-                self.mode = AppMode::PdfRender;
-            },
+                let opened = self.open_pdf();
+                if opened {
+                    self.mode = AppMode::PdfRender;
+                }
+            }
             _ => {}
         }
     }
@@ -191,6 +1122,7 @@ impl App {
                 if let Some(&(x, y)) = self.keyboard_grid.get(&ch) {
                     self.cursor_x = (x as f64).min(self.canvas_width as f64 - 1.0);
                     self.cursor_y = (y as f64).min(self.canvas_height as f64 - 1.0);
+                    self.cursor_jumped();
                     self.mode = AppMode::Drawing;
                 }
             }
@@ -205,8 +1137,20 @@ impl App {
                 self.color_input.clear();
             }
             KeyCode::Enter => {
-                if let Some(color) = self.parse_hex_color(&self.color_input) {
-                    self.current_color = color;
+                // "RRGGBB" sets the color, "RRGGBB>RRGGBB" a gradient for line tools
+                match self.color_input.split_once('>') {
+                    Some((start, end)) => {
+                        if let (Some(start), Some(end)) = (self.parse_hex_color(start), self.parse_hex_color(end)) {
+                            self.current_color = start;
+                            self.gradient = Some((start, end));
+                        }
+                    }
+                    None => {
+                        if let Some(color) = self.parse_hex_color(&self.color_input) {
+                            self.current_color = color;
+                            self.gradient = None;
+                        }
+                    }
                 }
                 self.mode = AppMode::Drawing;
                 self.color_input.clear();
@@ -214,10 +1158,9 @@ impl App {
             KeyCode::Backspace => {
                 self.color_input.pop();
             }
-            KeyCode::Char(ch) => {
-                if ch.is_ascii_hexdigit() && self.color_input.len() < 6 {
-                    self.color_input.push(ch.to_ascii_uppercase());
-                }
+            KeyCode::Char('>') if self.color_input.len() == 6 => self.color_input.push('>'),
+            KeyCode::Char(ch) if ch.is_ascii_hexdigit() && self.color_input.len() != 6 && self.color_input.len() < 13 => {
+                self.color_input.push(ch.to_ascii_uppercase());
             }
             _ => {}
         }
@@ -237,53 +1180,123 @@ impl App {
             KeyCode::Backspace => {
                 self.coordinate_input.pop();
             }
-            KeyCode::Char(ch) => {
-                if (ch.is_ascii_digit() || ch == '.' || ch == ',' || ch == ' ' || ch == '-') 
-                   && self.coordinate_input.len() < 20 {
-                    self.coordinate_input.push(ch);
-                }
+            KeyCode::Char(ch) if (ch.is_ascii_digit() || ch == '.' || ch == ',' || ch == ' ' || ch == '-' || ch == '@')
+                && self.coordinate_input.len() < 20 => {
+                self.coordinate_input.push(ch);
             }
             _ => {}
         }
     }
 
+    // Canvas widget y for the cell containing a (possibly fractional) canvas
+    // row, honoring scroll. Axes, origin and cursor all go through this so the
+    // cell drawn for a position is always the one the cursor reports.
+    fn screen_y(&self, row: f64) -> f64 {
+        self.canvas_height as f64 - 1.0 - (row.floor() - self.scroll_y as f64)
+    }
+
+    // Convert a canvas cell position to data coordinates (Y grows upwards
+    // unless y_down is set)
+    fn cell_to_data(&self, x: f64, y: f64) -> (f64, f64) {
+        ((x - self.origin_x) * self.scale_x, (self.origin_y - y) * self.y_sign() * self.scale_y)
+    }
+
+    // Convert data coordinates back to a canvas cell position
+    fn data_to_cell(&self, x: f64, y: f64) -> (f64, f64) {
+        (self.origin_x + x / self.scale_x, self.origin_y - y * self.y_sign() / self.scale_y)
+    }
+
+    // +1 for the math convention (y up), -1 for screen/image coordinates
+    fn y_sign(&self) -> f64 {
+        if self.y_down { -1.0 } else { 1.0 }
+    }
+
     fn parse_and_move_to_coordinate(&mut self) {
-        let parts: Vec<&str> = self.coordinate_input.split(',').collect();
-        
-        match self.coordinate_system {
-            CoordinateSystem::Cartesian => {
-                if parts.len() >= 2 {
-                    if let (Ok(x), Ok(y)) = (parts[0].trim().parse::<f64>(), parts[1].trim().parse::<f64>()) {
-                        self.cursor_x = (self.origin_x + x).clamp(0.0, self.canvas_width as f64 - 1.0);
-                        self.cursor_y = (self.origin_y - y).clamp(0.0, self.canvas_height as f64 - 1.0); // Flip Y for screen coords
-                    }
+        let input = self.coordinate_input.trim().to_string();
+        // "x0,y0 - x1,y1" draws a segment; the spaces keep it apart from negative numbers
+        if let Some((from, to)) = input.split_once(" - ") {
+            let cursor = (self.cursor_x, self.cursor_y);
+            let start = self.parse_coordinate(from, cursor);
+            // A relative end point ("@dx,dy") is measured from the start point
+            let end = start.clone().and_then(|start| self.parse_coordinate(to, start));
+            let size = (self.canvas_width as f64, self.virtual_height as f64);
+            match (start, end) {
+                (Ok((x0, y0)), Ok((x1, y1))) if clip_segment((x0, y0), (x1, y1), (0.0, 0.0), size).is_none() => {
+                    self.status_message = Some(format!("{} - {} lies off the canvas", from.trim(), to.trim()));
                 }
+                (Ok((x0, y0)), Ok((x1, y1))) => {
+                    self.push_undo();
+                    self.draw_tool_line(x0, y0, x1, y1);
+                    self.cursor_x = x1.clamp(0.0, self.canvas_width as f64 - 1.0);
+                    self.cursor_y = y1.clamp(0.0, self.canvas_height as f64 - 1.0);
+                    self.cursor_jumped();
+                    self.status_message = Some(format!("Drew {} to {}", from.trim(), to.trim()));
+                }
+                (Err(message), _) | (_, Err(message)) => self.status_message = Some(message),
+            }
+            return;
+        }
+
+        let (cx, cy) = match self.parse_coordinate(&input, (self.cursor_x, self.cursor_y)) {
+            Ok(cell) => cell,
+            Err(message) => {
+                self.status_message = Some(message);
+                return;
             }
+        };
+        self.cursor_x = cx.clamp(0.0, self.canvas_width as f64 - 1.0);
+        self.cursor_y = cy.clamp(0.0, self.canvas_height as f64 - 1.0);
+        self.cursor_jumped();
+        if self.cursor_x != cx || self.cursor_y != cy {
+            self.status_message = Some(format!("{} is off-canvas, clamped to the edge", input));
+        }
+    }
+
+    // Canvas position (unclamped) of a typed coordinate in the active system.
+    // A leading '@' makes it an offset from the canvas position `from`, CAD style.
+    fn parse_coordinate(&self, input: &str, from: (f64, f64)) -> std::result::Result<(f64, f64), String> {
+        let (relative, input) = match input.trim().strip_prefix('@') {
+            Some(rest) => (true, rest),
+            None => (false, input),
+        };
+        // Accept commas and/or whitespace as separators: "3,-4", "3 -4", "3, -4"
+        let parts: Vec<&str> = input
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|part| !part.is_empty())
+            .collect();
+        let (needed, expected) = match self.coordinate_system {
+            CoordinateSystem::Cartesian => (2, "x,y"),
+            CoordinateSystem::Polar => (2, "r,θ"),
+            CoordinateSystem::Cylindrical => (3, "ρ,θ,z"),
+        };
+        let values: Option<Vec<f64>> = parts.iter().map(|part| part.parse::<f64>().ok().filter(|v| v.is_finite())).collect();
+        let values = match values {
+            Some(values) if values.len() == needed => values,
+            _ => return Err(format!("Invalid coordinate '{}': expected {}", input.trim(), expected)),
+        };
+
+        let (x, y) = match self.coordinate_system {
+            CoordinateSystem::Cartesian => (values[0], values[1]),
             CoordinateSystem::Polar => {
-                if parts.len() >= 2 {
-                    if let (Ok(r), Ok(theta)) = (parts[0].trim().parse::<f64>(), parts[1].trim().parse::<f64>()) {
-                        let x = r * theta.to_radians().cos();
-                        let y = r * theta.to_radians().sin();
-                        self.cursor_x = (self.origin_x + x).clamp(0.0, self.canvas_width as f64 - 1.0);
-                        self.cursor_y = (self.origin_y - y).clamp(0.0, self.canvas_height as f64 - 1.0);
-                    }
-                }
+                let (r, theta) = (values[0], values[1]);
+                (r * theta.to_radians().cos(), r * theta.to_radians().sin())
             }
             CoordinateSystem::Cylindrical => {
-                if parts.len() >= 3 {
-                    if let (Ok(r), Ok(theta), Ok(z)) = (
-                        parts[0].trim().parse::<f64>(), 
-                        parts[1].trim().parse::<f64>(), 
-                        parts[2].trim().parse::<f64>()
-                    ) {
-                        let x = r * theta.to_radians().cos();
-                        let y = r * theta.to_radians().sin() + z * 0.1; // Simple z representation
-                        self.cursor_x = (self.origin_x + x).clamp(0.0, self.canvas_width as f64 - 1.0);
-                        self.cursor_y = (self.origin_y - y).clamp(0.0, self.canvas_height as f64 - 1.0);
-                    }
-                }
+                let (r, theta, z) = (values[0], values[1], values[2]);
+                (r * theta.to_radians().cos(), r * theta.to_radians().sin() + z * 0.1) // Simple z representation
             }
+        };
+        let cell = if relative {
+            let (base_x, base_y) = self.cell_to_data(from.0, from.1);
+            self.data_to_cell(base_x + x, base_y + y)
+        } else {
+            self.data_to_cell(x, y)
+        };
+        // Huge offsets can still overflow once scaled
+        if !(cell.0.is_finite() && cell.1.is_finite()) {
+            return Err(format!("Coordinate '{}' is out of range", input.trim()));
         }
+        Ok(cell)
     }
 
     fn handle_typst_input_keys(&mut self, key: KeyEvent) {
@@ -291,56 +1304,86 @@ impl App {
             KeyCode::Esc => {
                 self.mode = AppMode::Drawing;
                 self.text_buffer.clear();
+                self.pending_closers.clear();
             }
             KeyCode::Enter => {
+                // Auto-inserted closers still waiting to be typed over are part of the text
+                let closers = std::mem::take(&mut self.pending_closers);
+                self.text_buffer.push_str(&closers);
                 // Place the text on canvas AND save to typst content
                 if !self.text_buffer.is_empty() {
-                    // Place text on canvas at current cursor position
-                    for (i, ch) in self.text_buffer.chars().enumerate() {
-                        let x = (self.cursor_x as usize + i).min(self.canvas_width - 1);
-                        let y = self.cursor_y as usize;
-                        if x < self.canvas_width && y < self.virtual_height {
-                            self.canvas[y][x] = Some(DrawChar::Text(ch));
+                    // Place text on canvas at current cursor position, showing
+                    // `$...$` math in a Unicode approximation of its typeset form
+                    self.push_undo();
+                    let is_math = self.text_buffer.len() > 1
+                        && self.text_buffer.starts_with('$')
+                        && self.text_buffer.ends_with('$');
+                    let text: Vec<char> = if self.pretty_math && is_math {
+                        prettify_math(&self.text_buffer).chars().collect()
+                    } else {
+                        self.text_buffer.chars().collect()
+                    };
+                    let (x, y) = (self.cursor_x as usize, self.cursor_y as usize);
+                    let mut clipped = 0;
+                    for (i, ch) in text.into_iter().enumerate() {
+                        if !self.set_cell(x + i, y, DrawChar::Text(ch)) && x + i >= self.canvas_width {
+                            clipped += 1;
                         }
                     }
+                    if clipped > 0 {
+                        self.status_message = Some(format!("{} characters past the right edge were clipped", clipped));
+                    }
                     
                     // Also save to typst content for export
-                    self.typst_content.push(self.text_buffer.clone());
+                    self.typst_content.push(TypstLine {
+                        text: self.text_buffer.clone(),
+                        attrs: self.text_attrs,
+                    });
                     self.text_buffer.clear();
                     
                     // Move cursor to next line
-                    self.cursor_y += 1.0;
+                    self.cursor_y = (self.cursor_y + 1.0).min(self.virtual_height as f64 - 1.0);
                     self.cursor_x = self.origin_x; // Reset to left margin
                 }
                 self.mode = AppMode::Drawing; // Return to drawing mode
             }
             KeyCode::Backspace => {
-                if !self.text_buffer.is_empty() {
-                    self.text_buffer.pop();
+                if let Some(ch) = self.text_buffer.pop() {
+                    // Deleting an opener also drops its untouched auto-inserted closer
+                    let closer = self.pairs.iter().find(|&&(open, _)| open == ch).map(|&(_, close)| close);
+                    if closer.is_some() && closer == self.pending_closers.chars().next() {
+                        self.pending_closers.remove(0);
+                    }
                 } else if !self.typst_content.is_empty() {
                     // Edit the last typst line
                     if let Some(last_line) = self.typst_content.last_mut() {
-                        if last_line.is_empty() {
+                        if last_line.text.is_empty() {
                             self.typst_content.pop();
                         } else {
-                            last_line.pop();
+                            last_line.text.pop();
                         }
                     }
                 }
             }
-            KeyCode::Char(ch) => {
-                if ch != '\0' && !ch.is_control() {
-                    self.text_buffer.push(ch);
-                    
-                    // Auto-completion for paired characters
-                    match ch {
-                        '(' => self.text_buffer.push(')'),
-                        '[' => self.text_buffer.push(']'),
-                        '{' => self.text_buffer.push('}'),
-                        '$' => self.text_buffer.push('$'),
-                        '"' => self.text_buffer.push('"'),
-                        '\'' => self.text_buffer.push('\''),
-                        _ => {}
+            // Text attributes
+            KeyCode::Char('b') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                self.text_attrs.bold = !self.text_attrs.bold;
+            }
+            KeyCode::Char('t') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                self.text_attrs.italic = !self.text_attrs.italic;
+            }
+            KeyCode::Char('u') if key.modifiers.contains(KeyModifiers::CONTROL) => {
+                self.text_attrs.underline = !self.text_attrs.underline;
+            }
+            KeyCode::Char(ch) if ch != '\0' && !ch.is_control() && !key.modifiers.contains(KeyModifiers::CONTROL) => {
+                self.text_buffer.push(ch);
+
+                // Typing a pending closer steps over it instead of doubling it
+                if self.pending_closers.starts_with(ch) {
+                    self.pending_closers.remove(0);
+                } else if self.auto_pair {
+                    if let Some(&(_, close)) = self.pairs.iter().find(|&&(open, _)| open == ch) {
+                        self.pending_closers.insert(0, close);
                     }
                 }
             }
@@ -348,32 +1391,618 @@ impl App {
         }
     }
 
-    fn handle_settings_keys(&mut self, key: KeyEvent) {
-        match key.code {
-            KeyCode::Esc | KeyCode::Char('?') => self.mode = AppMode::Drawing,
-            KeyCode::Char('a') => self.show_axes = !self.show_axes,
-            KeyCode::Char('n') => self.grid_snap = !self.grid_snap,
-            KeyCode::Char('d') => self.continuous_draw = !self.continuous_draw,
-            KeyCode::Char('1') => self.coordinate_system = CoordinateSystem::Cartesian,
-            KeyCode::Char('2') => self.coordinate_system = CoordinateSystem::Polar,
-            KeyCode::Char('3') => self.coordinate_system = CoordinateSystem::Cylindrical,
-            _ => {}
-        }
+    // Toggles reachable from both Drawing and Settings share these so the two
+    // bindings can't drift apart
+    fn toggle_axes(&mut self) {
+        self.show_axes = !self.show_axes;
     }
-    
-    fn handle_pdf_render_keys(&mut self, key: KeyEvent) {
-        match key.code {
-            KeyCode::Esc | KeyCode::Char('r') => self.mode = AppMode::Drawing,
-            _ => {}
+
+    fn toggle_grid_snap(&mut self) {
+        self.grid_snap = !self.grid_snap;
+    }
+
+    fn toggle_continuous_draw(&mut self) {
+        self.continuous_draw = !self.continuous_draw;
+        if self.continuous_draw {
+            self.begin_stroke();
+        } else if self.smooth_strokes {
+            self.smooth_stroke();
+        }
+    }
+
+    fn begin_stroke(&mut self) {
+        self.stroke_path = vec![(self.cursor_x, self.cursor_y)];
+        self.stroke_base = Some(self.canvas.clone());
+        self.stroke_version = self.canvas_version;
+    }
+
+    // Redraw the last continuous-draw stroke as a Catmull-Rom curve through a
+    // thinned-out copy of its cursor path
+    fn smooth_stroke(&mut self) {
+        if self.stroke_version != self.canvas_version {
+            self.status_message = Some("Canvas changed since the last stroke, nothing to smooth".to_string());
+            return;
+        }
+        let Some(base) = self.stroke_base.clone() else {
+            self.status_message = Some("No stroke to smooth (draw one with d)".to_string());
+            return;
+        };
+        // Staircases from hjkl moves need thinning or the curve would follow them
+        let mut points: Vec<(f64, f64)> = Vec::new();
+        for &(x, y) in &self.stroke_path {
+            match points.last() {
+                Some(&(px, py)) if (x - px).hypot(y - py) < 3.0 => {}
+                _ => points.push((x, y)),
+            }
+        }
+        if let (Some(&end), Some(&last)) = (self.stroke_path.last(), points.last()) {
+            if end != last {
+                points.push(end);
+            }
+        }
+        if points.len() < 3 {
+            return;
+        }
+
+        self.push_undo();
+        self.canvas = base;
+        let mut previous = points[0];
+        for i in 0..points.len() - 1 {
+            let p0 = points[i.saturating_sub(1)];
+            let (p1, p2) = (points[i], points[i + 1]);
+            let p3 = points[(i + 2).min(points.len() - 1)];
+            let samples = ((p2.0 - p1.0).hypot(p2.1 - p1.1) * 2.0).ceil().max(1.0) as usize;
+            for step in 1..=samples {
+                let point = catmull_rom(p0, p1, p2, p3, step as f64 / samples as f64);
+                self.draw_stroke_segment(previous, previous.0, previous.1, point.0, point.1, self.current_color);
+                previous = point;
+            }
+        }
+        self.canvas_version += 1;
+        self.stroke_version = self.canvas_version;
+        self.status_message = Some("Smoothed stroke".to_string());
+    }
+
+    fn set_coordinate_system(&mut self, system: CoordinateSystem) {
+        self.coordinate_system = system;
+    }
+
+    // What drawing with the current brush places; the point brush uses the
+    // chosen marker, kept as text unless it is the default dot
+    fn brush(&self) -> DrawChar {
+        match self.current_char {
+            DrawChar::Point if self.point_marker != DrawChar::Point.glyph() => DrawChar::Text(self.point_marker),
+            _ => self.current_char.clone(),
+        }
+    }
+
+    fn drawing_status(&self, verbosity: StatusVerbosity) -> String {
+        let name = match self.current_char {
+            DrawChar::Point => format!("point({})", self.point_marker),
+            _ => self.current_char.name(),
+        };
+        let brush = format!("Drawing: {} | width: {}", name, self.line_width);
+        let mut parts: Vec<String> = Vec::new();
+        if self.show_coordinates || verbosity == StatusVerbosity::Coordinates {
+            parts.push(self.get_current_coordinates());
+        }
+        if let Some(message) = &self.status_message {
+            parts.push(message.clone());
+        }
+        if let Some(keys) = &self.macro_recording {
+            parts.push(format!("● recording macro, {} keys (Q to stop)", keys.len()));
+        }
+        // Say why the cursor won't go further instead of silently stopping
+        if self.cursor_y >= self.virtual_height as f64 - 1.0 {
+            parts.push(format!("▼ last row {} (:grow to add rows)", self.virtual_height));
+        } else if self.cursor_y < 1.0 {
+            parts.push("▲ top row".to_string());
+        }
+        match verbosity {
+            StatusVerbosity::Full => {
+                if self.status_message.is_none() {
+                    parts.push("hjkl:move | space:draw | u:undo | i:text | y:plot | g:goto | s:save | x:color | J/K:scroll | ?:settings | q:quit".to_string());
+                }
+                parts.push(brush);
+            }
+            StatusVerbosity::Minimal => parts.push(brush),
+            StatusVerbosity::Coordinates => {}
+        }
+        parts.join(" | ")
+    }
+
+    fn handle_settings_keys(&mut self, key: KeyEvent) {
+        match key.code {
+            KeyCode::Esc | KeyCode::Char('?') => self.mode = AppMode::Drawing,
+            KeyCode::Char('h') => self.mode = AppMode::Help,
+            KeyCode::Char('a') => self.toggle_axes(),
+            KeyCode::Char('s') => {
+                self.axis_style = match self.axis_style {
+                    AxisStyle::Full => AxisStyle::Ticks,
+                    AxisStyle::Ticks => AxisStyle::Hidden,
+                    AxisStyle::Hidden => AxisStyle::Full,
+                };
+            }
+            KeyCode::Char('l') => {
+                self.status_verbosity = match self.status_verbosity {
+                    StatusVerbosity::Full => StatusVerbosity::Minimal,
+                    StatusVerbosity::Minimal => StatusVerbosity::Coordinates,
+                    StatusVerbosity::Coordinates => StatusVerbosity::Full,
+                };
+            }
+            KeyCode::Char('n') => self.toggle_grid_snap(),
+            KeyCode::Char('m') => self.data_snap = !self.data_snap,
+            KeyCode::Char('o') => self.origin_snap = !self.origin_snap,
+            KeyCode::Char('x') => self.axis_snap = !self.axis_snap,
+            KeyCode::Char('c') => self.show_coordinates = !self.show_coordinates,
+            KeyCode::Char('t') => self.show_tooltip = !self.show_tooltip,
+            KeyCode::Char('w') => self.line_width = self.line_width % 3 + 1,
+            KeyCode::Char('r') => self.merge_draw = !self.merge_draw,
+            KeyCode::Char('f') => self.figure_export = !self.figure_export,
+            KeyCode::Char('E') => self.exact_grid = !self.exact_grid,
+            KeyCode::Char('M') => self.auto_math = !self.auto_math,
+            KeyCode::Char('B') => {
+                let next = EMPTY_CELL_CHARS.iter().position(|&c| c == self.empty_cell).map_or(0, |i| (i + 1) % EMPTY_CELL_CHARS.len());
+                // A placeholder that is also drawn would be erased on reload
+                if self.canvas_contains(EMPTY_CELL_CHARS[next]) {
+                    self.status_message = Some(format!("'{}' is drawn on the canvas, keeping blanks as spaces", EMPTY_CELL_CHARS[next]));
+                    self.empty_cell = ' ';
+                } else {
+                    self.empty_cell = EMPTY_CELL_CHARS[next];
+                }
+            }
+            KeyCode::Char('N') => self.snap_preview = !self.snap_preview,
+            KeyCode::Char('T') => {
+                let next = THEMES.iter().position(|theme| theme.name == self.theme.name).map_or(0, |i| (i + 1) % THEMES.len());
+                self.set_theme(THEMES[next]);
+            }
+            KeyCode::Char('i') => self.import_wrap = !self.import_wrap,
+            KeyCode::Char('e') => self.smooth_strokes = !self.smooth_strokes,
+            KeyCode::Char('p') => self.pretty_math = !self.pretty_math,
+            KeyCode::Char('b') => self.blink_cursor = !self.blink_cursor,
+            KeyCode::Char('v') => {
+                self.typst_available = typst_on_path();
+                self.mode = AppMode::About;
+            }
+            KeyCode::Char('d') => self.toggle_continuous_draw(),
+            KeyCode::Char('1') => self.set_coordinate_system(CoordinateSystem::Cartesian),
+            KeyCode::Char('2') => self.set_coordinate_system(CoordinateSystem::Polar),
+            KeyCode::Char('3') => self.set_coordinate_system(CoordinateSystem::Cylindrical),
+            // Axis scale
+            KeyCode::Char('<') => {
+                self.scale_x = (self.scale_x / 2.0).abs().max(MIN_SCALE).copysign(self.scale_x);
+                self.scale_y = (self.scale_y / 2.0).abs().max(MIN_SCALE).copysign(self.scale_y);
+            }
+            KeyCode::Char('>') => {
+                self.scale_x = (self.scale_x * 2.0).abs().min(MAX_SCALE).copysign(self.scale_x);
+                self.scale_y = (self.scale_y * 2.0).abs().min(MAX_SCALE).copysign(self.scale_y);
+            }
+            KeyCode::Char('z') => self.spawn_pdf_viewer = !self.spawn_pdf_viewer,
+            KeyCode::Char('y') => self.y_down = !self.y_down,
+            KeyCode::Char('u') => self.show_gridlines = !self.show_gridlines,
+            KeyCode::Char('q') => self.show_rulers = !self.show_rulers,
+            KeyCode::Char('k') => self.center_cursor = !self.center_cursor,
+            KeyCode::Char('j') => self.auto_pair = !self.auto_pair,
+            KeyCode::Char('g') => {
+                self.grid_offset_x = 0.0;
+                self.grid_offset_y = 0.0;
+            }
+            // Data grid spacing
+            KeyCode::Char('[') => self.data_snap_step = (self.data_snap_step / 2.0).max(MIN_SCALE),
+            KeyCode::Char(']') => self.data_snap_step = (self.data_snap_step * 2.0).min(MAX_SCALE),
+            _ => {}
+        }
+    }
+    
+    fn handle_polyline_keys(&mut self, key: KeyEvent) {
+        match key.code {
+            KeyCode::Esc => self.finish_polyline(),
+            KeyCode::Char('h') => self.move_cursor(-1.0, 0.0),
+            KeyCode::Char('j') => self.move_cursor(0.0, 1.0),
+            KeyCode::Char('k') => self.move_cursor(0.0, -1.0),
+            KeyCode::Char('l') => self.move_cursor(1.0, 0.0),
+            KeyCode::Char(' ') | KeyCode::Enter => self.add_polyline_vertex(),
+            KeyCode::Char('a') => self.angle_snap = !self.angle_snap,
+            KeyCode::Char('e') => self.endpoint_snap = !self.endpoint_snap,
+            KeyCode::Char('c') => {
+                // Close the polygon back to the first vertex
+                if self.polyline.len() > 2 {
+                    self.push_undo();
+                    let (x0, y0) = self.polyline[self.polyline.len() - 1];
+                    let (x1, y1) = self.polyline[0];
+                    self.draw_tool_line(x0, y0, x1, y1);
+                }
+                self.finish_polyline();
+            }
+            KeyCode::Char('F') => {
+                // Close the polygon and fill its interior
+                if self.polyline.len() > 2 {
+                    self.push_undo();
+                    let (x0, y0) = self.polyline[self.polyline.len() - 1];
+                    let (x1, y1) = self.polyline[0];
+                    self.draw_tool_line(x0, y0, x1, y1);
+                    let vertices = self.polyline.clone();
+                    self.fill_polygon(&vertices, self.brush());
+                }
+                self.finish_polyline();
+            }
+            _ => {}
+        }
+    }
+
+    // Where the next polyline vertex lands: the cursor, or with angle snap on
+    // the nearest point on a 0/45/90° ray from the previous vertex, then
+    // pulled onto a nearby line end so connected shapes stay connected
+    fn polyline_target(&self) -> (f64, f64) {
+        let (x, y) = match self.polyline.last() {
+            Some(&(x0, y0)) if self.angle_snap => constrain_45(x0, y0, self.cursor_x, self.cursor_y),
+            _ => (self.cursor_x, self.cursor_y),
+        };
+        self.endpoint_near(x, y).unwrap_or((x, y))
+    }
+
+    // Closest drawn cell within ENDPOINT_SNAP_RADIUS that ends a stroke: a
+    // glyph with at most one drawn neighbour. The vertex just placed is
+    // skipped so the next one can still go right next to it.
+    fn endpoint_near(&self, x: f64, y: f64) -> Option<(f64, f64)> {
+        if !self.endpoint_snap {
+            return None;
+        }
+        let drawn = |x: i32, y: i32| {
+            x >= 0 && y >= 0 && self.canvas
+                .get(y as usize)
+                .and_then(|row| row.get(x as usize))
+                .is_some_and(|cell| cell.as_ref().is_some_and(|cell| !matches!(cell.ch, DrawChar::Text(_))))
+        };
+        let last = self.polyline.last().map(|&(x, y)| (x.floor() as i32, y.floor() as i32));
+        let (cx, cy) = (x.floor() as i32, y.floor() as i32);
+        let r = ENDPOINT_SNAP_RADIUS;
+        (-r..=r)
+            .flat_map(|dy| (-r..=r).map(move |dx| (cx + dx, cy + dy)))
+            .filter(|&(ex, ey)| drawn(ex, ey) && Some((ex, ey)) != last)
+            .filter(|&(ex, ey)| {
+                let neighbours = (-1..=1)
+                    .flat_map(|dy| (-1..=1).map(move |dx| (dx, dy)))
+                    .filter(|&(dx, dy)| (dx, dy) != (0, 0) && drawn(ex + dx, ey + dy))
+                    .count();
+                neighbours <= 1
+            })
+            .min_by_key(|&(ex, ey)| (ex - cx).pow(2) + (ey - cy).pow(2))
+            .map(|(ex, ey)| (ex as f64, ey as f64))
+    }
+
+    fn add_polyline_vertex(&mut self) {
+        self.push_undo();
+        let vertex = self.polyline_target();
+        self.cursor_x = vertex.0.clamp(0.0, self.canvas_width as f64 - 1.0);
+        self.cursor_y = vertex.1.clamp(0.0, self.virtual_height as f64 - 1.0);
+        match self.polyline.last() {
+            Some(&(x0, y0)) => self.draw_tool_line(x0, y0, vertex.0, vertex.1),
+            None => self.draw_char(),
+        }
+        self.polyline.push(vertex);
+    }
+
+    fn finish_polyline(&mut self) {
+        self.polyline.clear();
+        self.mode = AppMode::Drawing;
+    }
+
+    fn handle_command_keys(&mut self, key: KeyEvent) {
+        match key.code {
+            KeyCode::Esc => {
+                self.mode = AppMode::Drawing;
+                self.command_input.clear();
+                self.function_editing = None;
+            }
+            KeyCode::Enter => {
+                let command = std::mem::take(&mut self.command_input);
+                self.mode = AppMode::Drawing;
+                self.run_command(command.trim());
+                self.function_editing = None;
+            }
+            KeyCode::Backspace => {
+                self.command_input.pop();
+            }
+            KeyCode::Char(ch) if !key.modifiers.contains(KeyModifiers::CONTROL) => self.command_input.push(ch),
+            _ => {}
+        }
+    }
+
+    fn run_command(&mut self, command: &str) {
+        let (name, arg) = match command.split_once(' ') {
+            Some((name, arg)) => (name, arg.trim()),
+            None => (command, ""),
+        };
+
+        match name {
+            "w" | "wq" => {
+                if !arg.is_empty() {
+                    // Remember the name so plain `s` keeps saving here
+                    self.save_path = if Path::new(arg).extension().is_some() {
+                        arg.to_string()
+                    } else {
+                        format!("{}.typ", arg)
+                    };
+                }
+                self.save_typst();
+                if name == "wq" {
+                    self.should_quit = true;
+                }
+            }
+            "q" => self.should_quit = true,
+            "e" | "edit" => self.open_drawing(arg),
+            // Polar mode plots r = f(theta) instead
+            "plot" if matches!(self.coordinate_system, CoordinateSystem::Polar) => match Expr::parse(arg, &["theta"]) {
+                Ok(expr) => {
+                    self.push_undo();
+                    self.plot_polar(&expr);
+                    self.status_message = Some(format!("Plotted r = {}", arg));
+                }
+                Err(err) => self.status_message = Some(format!("Plot error: {}", err)),
+            },
+            "plot" => match Expr::parse(arg, &["x"]) {
+                Ok(expr) => {
+                    self.push_undo();
+                    self.plot_function(&expr);
+                    self.last_plot = Some(expr);
+                    self.status_message = Some(format!("Plotted y = {}", arg));
+                }
+                Err(err) => self.status_message = Some(format!("Plot error: {}", err)),
+            },
+            "fn" => self.add_function(arg),
+            "fns" => {
+                self.function_selected = self.function_selected.min(self.functions.len().saturating_sub(1));
+                self.mode = AppMode::Functions;
+            }
+            "shade" => self.shade_under_plot(arg),
+            "implicit" => self.plot_implicit(arg),
+            "field" => self.plot_vector_field(arg),
+            "minimap" => self.show_minimap = !self.show_minimap,
+            "macro" => match arg {
+                "" => self.play_macro(1),
+                _ => match arg.parse::<usize>() {
+                    Ok(count) if (1..=MACRO_REPEAT_LIMIT).contains(&count) => self.play_macro(count),
+                    _ => self.status_message = Some(format!("macro error: expected a count from 1 to {}", MACRO_REPEAT_LIMIT)),
+                },
+            },
+            "preview" => self.show_typst_preview = !self.show_typst_preview,
+            "precision" => match parse_precision(arg) {
+                Some(precision) => {
+                    self.coord_precision = precision;
+                    self.status_message = Some(match precision {
+                        Some(p) => format!("Coordinates show {} decimals", p),
+                        None => "Coordinate decimals follow the cursor".to_string(),
+                    });
+                }
+                None => self.status_message = Some("precision error: expected 0-6 or auto".to_string()),
+            },
+            "strokebrush" => {
+                // `:strokebrush auto|direction|brush|<brush name>`, no argument just reports
+                match arg {
+                    "" => {}
+                    "brush" => self.stroke_brush = StrokeBrush::Brush,
+                    "auto" => self.stroke_brush = StrokeBrush::Auto,
+                    "direction" => self.stroke_brush = StrokeBrush::Direction,
+                    _ => match DrawChar::from_name(arg) {
+                        Some(brush) => self.stroke_brush = StrokeBrush::Fixed(brush),
+                        None => {
+                            self.status_message = Some(format!("strokebrush error: unknown brush '{}'", arg));
+                            return;
+                        }
+                    },
+                }
+                self.status_message = Some(format!("Stroke brush: {}", match &self.stroke_brush {
+                    StrokeBrush::Brush => "brush".to_string(),
+                    StrokeBrush::Auto => "auto".to_string(),
+                    StrokeBrush::Direction => "direction".to_string(),
+                    StrokeBrush::Fixed(brush) => brush.name(),
+                }));
+            }
+            "pairs" => {
+                // Consecutive opener/closer characters, e.g. `:pairs ()[]$$`
+                let chars: Vec<char> = arg.chars().filter(|c| !c.is_whitespace()).collect();
+                if !chars.len().is_multiple_of(2) {
+                    self.status_message = Some("pairs error: expected opener/closer pairs like ()[]$$".to_string());
+                } else {
+                    if !chars.is_empty() {
+                        self.pairs = chars.chunks_exact(2).map(|pair| (pair[0], pair[1])).collect();
+                    }
+                    let table: String = self.pairs.iter().flat_map(|&(open, close)| [open, close]).collect();
+                    self.status_message = Some(format!("Auto-pairs: {}", table));
+                }
+            }
+            "page" => {
+                let paper = match arg.to_lowercase().as_str() {
+                    "a4" => Ok(Some(PaperSize::A4)),
+                    "letter" | "us-letter" => Ok(Some(PaperSize::Letter)),
+                    "off" | "" => Ok(None),
+                    _ => Err(()),
+                };
+                match paper {
+                    Ok(paper) => {
+                        self.fit_page = paper;
+                        self.status_message = Some(match paper {
+                            Some(paper) => format!("Fit to {} page: {:.1}pt text", paper.typst_name(), self.fit_text_size(paper)),
+                            None => "Fit to page off".to_string(),
+                        });
+                    }
+                    Err(()) => self.status_message = Some("page error: expected a4, letter or off".to_string()),
+                }
+            }
+            "calibrate" => self.calibrate(arg),
+            "window" => self.set_window(arg),
+            "aspect" => match arg.parse::<f64>() {
+                Ok(ratio) if ratio > 0.0 && ratio.is_finite() => {
+                    self.cell_aspect = ratio;
+                    self.status_message = Some(format!("Cell aspect (width:height): {}", ratio));
+                }
+                _ => self.status_message = Some("aspect error: expected a positive width:height ratio, e.g. 0.5".to_string()),
+            },
+            "gridoffset" => {
+                let values: std::result::Result<Vec<f64>, _> = arg.split(|c: char| c == ',' || c.is_whitespace())
+                    .filter(|part| !part.is_empty())
+                    .map(str::parse)
+                    .collect();
+                match values.as_deref() {
+                    Ok(&[x, y]) if x.is_finite() && y.is_finite() => {
+                        self.grid_offset_x = x;
+                        self.grid_offset_y = y;
+                        self.status_message = Some(format!("Grid offset: ({}, {})", x, y));
+                    }
+                    _ => self.status_message = Some("gridoffset error: expected x y".to_string()),
+                }
+            }
+            "caption" => {
+                self.figure_caption = arg.to_string();
+                self.status_message = Some(format!("Figure caption: {}", arg));
+            }
+            "tabnew" => {
+                let save_path = if arg.is_empty() {
+                    format!("drawing-{}.typ", self.tabs.len() + 1)
+                } else if Path::new(arg).extension().is_some() {
+                    arg.to_string()
+                } else {
+                    format!("{}.typ", arg)
+                };
+                self.tabs.push(Tab::new(self.canvas_width, self.virtual_height, save_path));
+                self.switch_tab(self.tabs.len() - 1);
+            }
+            "tabclose" => self.close_tab(),
+            "tabn" | "tabnext" => self.switch_tab((self.active_tab + 1) % self.tabs.len()),
+            "tabp" | "tabprev" => self.switch_tab((self.active_tab + self.tabs.len() - 1) % self.tabs.len()),
+            "ray" | "xline" => match arg.parse::<f64>().ok().filter(|angle| angle.is_finite()) {
+                Some(angle) => {
+                    self.push_undo();
+                    self.draw_ray(angle, name == "xline");
+                    self.status_message = Some(format!("Drew {} at {}°", name, angle));
+                }
+                None => self.status_message = Some(format!("{} error: expected an angle in degrees", name)),
+            },
+            "polygon" => self.draw_regular_polygon(arg),
+            "note" => match arg {
+                "" => self.status_message = Some("note error: expected the note text".to_string()),
+                _ => {
+                    let (x, y) = self.cell_to_data(self.cursor_x, self.cursor_y);
+                    self.annotations.push((x, y, arg.to_string()));
+                    self.status_message = Some(format!("Note pinned at {}", self.get_current_coordinates()));
+                }
+            },
+            "notes" => match arg {
+                "clear" => {
+                    self.annotations.clear();
+                    self.status_message = Some("Notes cleared".to_string());
+                }
+                _ => self.status_message = Some(format!("{} notes (:notes clear removes them)", self.annotations.len())),
+            },
+            "rotate" => match arg.parse::<f64>().ok().filter(|angle| angle.is_finite()) {
+                Some(angle) => {
+                    self.push_undo();
+                    let moved = self.rotate_canvas(angle);
+                    self.status_message = Some(format!(
+                        "Rotated {} cells by {}° (each rotation loses detail, u to undo)",
+                        moved, angle
+                    ));
+                }
+                None => self.status_message = Some("rotate error: expected an angle in degrees".to_string()),
+            },
+            "heatmap" => self.load_heatmap(arg),
+            "import" => match std::fs::read_to_string(arg) {
+                Ok(contents) => {
+                    self.push_undo();
+                    let rows = self.import_text(&contents);
+                    self.status_message = Some(format!("Imported {} rows from {}", rows, arg));
+                }
+                Err(err) => self.status_message = Some(format!("Import error: {}: {}", arg, err)),
+            },
+            "smooth" => self.smooth_stroke(),
+            "grow" => match arg {
+                "" => self.grow_canvas(100),
+                _ => match arg.parse::<usize>() {
+                    Ok(rows) if rows > 0 => self.grow_canvas(rows),
+                    _ => self.status_message = Some("grow error: expected a number of rows".to_string()),
+                },
+            },
+            "replay" => match arg {
+                "" => self.start_replay(REPLAY_DELAY_MS),
+                _ => match arg.parse::<u128>() {
+                    Ok(delay) => self.start_replay(delay),
+                    Err(_) => self.status_message = Some("replay error: expected a delay in milliseconds".to_string()),
+                },
+            },
+            "stamp" => self.define_stamp(arg),
+            "place" => self.place_stamp(arg),
+            "table" => self.stamp_table(arg),
+            "export" => self.export_region(arg),
+            "stamps" => {
+                let mut names: Vec<&String> = self.stamps.keys().collect();
+                names.sort();
+                self.status_message = Some(if names.is_empty() {
+                    "No stamps defined (:stamp name w h)".to_string()
+                } else {
+                    format!("Stamps: {}", names.iter().map(|n| n.as_str()).collect::<Vec<_>>().join(", "))
+                });
+            }
+            "" => {}
+            _ => self.status_message = Some(format!("Unknown command: {}", name)),
+        }
+    }
+
+    fn handle_save_menu_keys(&mut self, key: KeyEvent) {
+        match key.code {
+            KeyCode::Esc => self.mode = AppMode::Drawing,
+            KeyCode::Char('t') | KeyCode::Enter => {
+                self.mode = AppMode::Drawing;
+                self.save_typst();
+            }
+            KeyCode::Char('k') => {
+                self.mode = AppMode::Drawing;
+                self.save_tikz();
+            }
+            KeyCode::Char('v') => {
+                self.mode = AppMode::Drawing;
+                self.save_snapshot();
+            }
+            _ => {}
+        }
+    }
+
+    fn handle_info_screen_keys(&mut self, key: KeyEvent) {
+        match key.code {
+            KeyCode::Esc => self.mode = AppMode::Drawing,
+            KeyCode::Char('?') => self.mode = AppMode::Settings,
+            _ => {}
+        }
+    }
+
+    fn handle_pdf_render_keys(&mut self, key: KeyEvent) {
+        match key.code {
+            KeyCode::Esc | KeyCode::Char('r') => self.mode = AppMode::Drawing,
+            _ => {}
         }
     }
     
-    fn open_pdf(&self) {
+    // Returns false when no viewer was opened or reused
+    fn open_pdf(&mut self) -> bool {
         use std::process::Command;
         use std::env;
+
+        if !self.spawn_pdf_viewer {
+            self.status_message = Some("Viewer spawning is off (settings: z)".to_string());
+            return false;
+        }
+        // Reuse a viewer that is still running instead of piling up windows
+        if let Some(child) = &mut self.pdf_viewer {
+            if matches!(child.try_wait(), Ok(None)) {
+                self.status_message = Some("PDF viewer already open".to_string());
+                return true;
+            }
+            self.pdf_viewer = None;
+        }
         
         let current_dir = env::current_dir().unwrap();
-        let pdf_file = format!("{}/drawing.pdf", current_dir.display());
+        let pdf_path = current_dir.join(&self.save_path).with_extension("pdf");
+        let pdf_file = pdf_path.display().to_string();
         
         // Open PDF with tdf in a new terminal
         let terminals = [("alacritty", vec!["-e", "tdf", &pdf_file]),
@@ -382,14 +2011,84 @@ impl App {
                         ("konsole", vec!["-e", "tdf", &pdf_file])];
         
         for (terminal, args) in &terminals {
-            if let Ok(_) = Command::new(terminal)
-                .args(args)
-                .spawn() {
-                break;
+            if let Ok(child) = Command::new(terminal).args(args).spawn() {
+                self.pdf_viewer = Some(child);
+                return true;
             }
         }
+        self.status_message = Some("No terminal found to run tdf".to_string());
+        false
     }
     
+    fn set_origin_at_cursor(&mut self) {
+        let snapped = if self.origin_snap {
+            self.nearest_filled_cell(self.cursor_x, self.cursor_y)
+        } else {
+            None
+        };
+
+        match snapped {
+            Some((x, y)) => {
+                self.origin_x = x as f64;
+                self.origin_y = y as f64;
+                self.status_message = Some(format!("Origin snapped to cell ({}, {})", x, y));
+            }
+            None => {
+                self.origin_x = self.cursor_x;
+                self.origin_y = self.cursor_y;
+            }
+        }
+    }
+
+    // Find the drawn cell closest to the given canvas position
+    fn nearest_filled_cell(&self, x: f64, y: f64) -> Option<(usize, usize)> {
+        let mut nearest = None;
+        let mut best_dist = f64::INFINITY;
+        for (row_idx, row) in self.canvas.iter().enumerate() {
+            for (col_idx, cell) in row.iter().enumerate() {
+                if cell.is_some() {
+                    let dist = (col_idx as f64 - x).powi(2) + (row_idx as f64 - y).powi(2);
+                    if dist < best_dist {
+                        best_dist = dist;
+                        nearest = Some((col_idx, row_idx));
+                    }
+                }
+            }
+        }
+        nearest
+    }
+
+    // Move the cursor to a canvas position and scroll so it sits mid-screen
+    fn jump_to(&mut self, x: f64, y: f64) {
+        self.cursor_x = x.clamp(0.0, self.canvas_width as f64 - 1.0);
+        self.cursor_y = y.clamp(0.0, self.virtual_height as f64 - 1.0);
+        self.center_scroll_on_cursor();
+        self.cursor_jumped();
+    }
+
+    fn center_scroll_on_cursor(&mut self) {
+        let max_scroll = self.virtual_height.saturating_sub(self.canvas_height);
+        self.scroll_y = (self.cursor_y as usize).saturating_sub(self.canvas_height / 2).min(max_scroll);
+    }
+
+    fn jump_to_content_center(&mut self) {
+        let mut bounds: Option<(usize, usize, usize, usize)> = None;
+        for (y, row) in self.canvas.iter().enumerate() {
+            for (x, cell) in row.iter().enumerate() {
+                if cell.is_some() {
+                    bounds = Some(match bounds {
+                        Some((x0, y0, x1, y1)) => (x0.min(x), y0.min(y), x1.max(x), y1.max(y)),
+                        None => (x, y, x, y),
+                    });
+                }
+            }
+        }
+        match bounds {
+            Some((x0, y0, x1, y1)) => self.jump_to(((x0 + x1) / 2) as f64, ((y0 + y1) / 2) as f64),
+            None => self.status_message = Some("Canvas is empty".to_string()),
+        }
+    }
+
     fn scroll_up(&mut self) {
         self.scroll_y = self.scroll_y.saturating_sub(3);
     }
@@ -399,196 +2098,2054 @@ impl App {
     }
 
     fn get_current_coordinates(&self) -> String {
-        let rel_x = self.cursor_x - self.origin_x;
-        let rel_y = self.origin_y - self.cursor_y; // Flip Y for mathematical coords
+        self.coordinates_at(self.cursor_x, self.cursor_y)
+    }
+
+    // Coordinates of a canvas position in the active coordinate system
+    fn coordinates_at(&self, x: f64, y: f64) -> String {
+        let (rel_x, rel_y) = self.cell_to_data(x, y);
+        // Adding zero turns -0.0 into 0.0 so the origin never reads "-0.0"
+        let (rel_x, rel_y) = (rel_x + 0.0, rel_y + 0.0);
+        // Whole numbers on the snapped grid, quarter cells from fine nudging,
+        // unless a fixed precision is configured
+        let p = if let Some(p) = self.coord_precision {
+            p
+        } else if self.grid_snap && rel_x.fract() == 0.0 && rel_y.fract() == 0.0 {
+            0
+        } else if x.fract() != 0.0 || y.fract() != 0.0 {
+            2
+        } else {
+            1
+        };
 
         match self.coordinate_system {
             CoordinateSystem::Cartesian => {
-                format!("({:.1}, {:.1})", rel_x, rel_y)
+                format!("({:.*}, {:.*})", p, rel_x, p, rel_y)
             }
             CoordinateSystem::Polar => {
                 let r = (rel_x * rel_x + rel_y * rel_y).sqrt();
                 let theta = rel_y.atan2(rel_x).to_degrees();
-                format!("(r:{:.1}, θ:{:.1}°)", r, theta)
+                format!("(r:{:.*}, θ:{:.*}°)", p, r, p, theta)
             }
             CoordinateSystem::Cylindrical => {
                 let r = (rel_x * rel_x).sqrt();
                 let theta = rel_y.atan2(rel_x).to_degrees();
                 let z = rel_y * 10.0; // Simple z representation
-                format!("(ρ:{:.1}, θ:{:.1}°, z:{:.1})", r, theta, z)
+                format!("(ρ:{:.*}, θ:{:.*}°, z:{:.*})", p, r, p, theta, p, z)
             }
         }
     }
 
-    fn move_cursor(&mut self, dx: f64, dy: f64) {
-        self.last_cursor_x = self.cursor_x;
-        self.last_cursor_y = self.cursor_y;
-        
+    // Where a (dx, dy) move lands after every active snap, and whether grid
+    // snapping pulled it off the raw position. Also drives the snap preview.
+    fn move_target(&self, dx: f64, dy: f64) -> ((f64, f64), bool) {
         let mut new_x = self.cursor_x + dx;
         let mut new_y = self.cursor_y + dy;
         
-        // Grid snapping
+        // Data grid snapping: step between round data values instead of cells
+        if self.data_snap {
+            (new_x, new_y) = self.step_on_data_grid(dx, dy);
+        }
+        
+        let mut pulled = false;
         if self.grid_snap {
+            let (raw_x, raw_y) = (new_x, new_y);
             new_x = new_x.round();
             new_y = new_y.round();
+            pulled = (raw_x, raw_y) != (new_x, new_y);
+        }
+        
+        // Axis snapping: land exactly on an axis when within one cell of it
+        if self.show_axes && self.axis_snap {
+            if (new_x - self.origin_x).abs() < 1.0 {
+                new_x = self.origin_x;
+            }
+            if (new_y - self.origin_y).abs() < 1.0 {
+                new_y = self.origin_y;
+            }
         }
         
-        self.cursor_x = new_x.max(0.0).min(self.canvas_width as f64 - 1.0);
-        self.cursor_y = new_y.max(0.0).min(self.virtual_height as f64 - 1.0);
+        let x = new_x.max(0.0).min(self.canvas_width as f64 - 1.0);
+        let y = new_y.max(0.0).min(self.virtual_height as f64 - 1.0);
+        ((x, y), pulled)
+    }
+
+    fn move_cursor(&mut self, dx: f64, dy: f64) {
+        self.last_cursor_x = self.cursor_x;
+        self.last_cursor_y = self.cursor_y;
+        
+        // Flash the cursor when grid snapping actually pulled the position
+        let ((x, y), pulled) = self.move_target(dx, dy);
+        if pulled {
+            self.snap_flash = Some(Instant::now());
+        }
+        self.cursor_x = x;
+        self.cursor_y = y;
         
+        // Keep the cursor mid-screen and pan the canvas under it
+        if self.center_cursor {
+            self.center_scroll_on_cursor();
+            return;
+        }
+
         // Auto-scroll to follow cursor
         let visible_start = self.scroll_y;
         let visible_end = self.scroll_y + self.canvas_height - 1;
         
         if (self.cursor_y as usize) < visible_start {
-            self.scroll_y = (self.cursor_y as usize).max(0);
+            self.scroll_y = self.cursor_y as usize;
         } else if (self.cursor_y as usize) > visible_end {
             self.scroll_y = ((self.cursor_y as usize) + 1).saturating_sub(self.canvas_height).min(self.virtual_height - self.canvas_height);
         }
-            
+    }
+
+    // Deliberate hjkl movement in drawing mode; the only movement that draws
+    // while continuous draw is on
+    fn draw_move(&mut self, dx: f64, dy: f64) {
+        self.move_cursor(dx, dy);
         if self.continuous_draw {
-            self.draw_line_to_cursor();
+            // One undo step per stroke: snapshot before its first segment, or
+            // again if something else edited the canvas since its last one
+            if self.stroke_path.len() <= 1 || self.stroke_version != self.canvas_version {
+                self.push_undo();
+            }
+            // Continuous strokes sweep the gradient back and forth as they grow
+            let color = match self.gradient {
+                Some((start, end)) => {
+                    let phase = (self.stroke_path.len() % (2 * GRADIENT_PERIOD)) as f64 / GRADIENT_PERIOD as f64;
+                    lerp_color(start, end, if phase > 1.0 { 2.0 - phase } else { phase })
+                }
+                None => self.current_color,
+            };
+            let from = match self.stroke_path.len() {
+                n if n >= 2 && self.stroke_brush == StrokeBrush::Direction => self.stroke_path[n - 2],
+                _ => (self.last_cursor_x, self.last_cursor_y),
+            };
+            self.draw_stroke_segment(from, self.last_cursor_x, self.last_cursor_y, self.cursor_x, self.cursor_y, color);
+            self.stroke_path.push((self.cursor_x, self.cursor_y));
+            self.stroke_version = self.canvas_version;
         }
     }
 
-    fn draw_line_to_cursor(&mut self) {
-        let x0 = self.last_cursor_x as i32;
-        let y0 = self.last_cursor_y as i32;
-        let x1 = self.cursor_x as i32;
-        let y1 = self.cursor_y as i32;
-        
-        // Bresenham's line algorithm
-        let dx = (x1 - x0).abs();
-        let dy = -(y1 - y0).abs();
-        let sx = if x0 < x1 { 1 } else { -1 };
-        let sy = if y0 < y1 { 1 } else { -1 };
-        let mut err = dx + dy;
-        
-        let mut x = x0;
-        let mut y = y0;
-        
-        loop {
-            if x >= 0 && x < self.canvas_width as i32 && y >= 0 && y < self.virtual_height as i32 {
-                self.canvas[y as usize][x as usize] = Some(self.current_char.clone());
-            }
-            
-            if x == x1 && y == y1 { break; }
-            
-            let e2 = 2 * err;
-            if e2 >= dy {
-                err += dy;
-                x += sx;
-            }
-            if e2 <= dx {
-                err += dx;
-                y += sy;
-            }
+    // Canvas rows and columns summarized by each minimap character so the
+    // whole canvas fits the minimap's inner area
+    fn minimap_block(&self, inner: Rect) -> (usize, usize) {
+        (
+            self.virtual_height.div_ceil(inner.height.max(1) as usize).max(1),
+            self.canvas_width.div_ceil(inner.width.max(1) as usize).max(1),
+        )
+    }
+
+    // Clicking a minimap line moves the cursor to that part of the canvas and
+    // scrolls it into the middle of the view
+    fn click_minimap(&mut self, area: Rect, row: u16) {
+        let inner = Block::default().borders(Borders::ALL).inner(area);
+        if row < inner.y || row >= inner.y + inner.height {
+            return;
         }
+        let (rows_per_line, _) = self.minimap_block(inner);
+        let target = ((row - inner.y) as usize * rows_per_line + rows_per_line / 2).min(self.virtual_height - 1);
+        self.cursor_y = target as f64;
+        self.cursor_jumped();
+        self.center_scroll_on_cursor();
     }
 
-    fn draw_char(&mut self) {
-        let x = self.cursor_x as usize;
-        let y = self.cursor_y as usize;
-        if x < self.canvas_width && y < self.virtual_height {
-            self.canvas[y][x] = Some(self.current_char.clone());
+    // Jumps (coordinate input, keyboard grid, tab switches) must not leave a
+    // line behind, so the next stroke starts from where the cursor landed
+    fn cursor_jumped(&mut self) {
+        self.last_cursor_x = self.cursor_x;
+        self.last_cursor_y = self.cursor_y;
+        if self.continuous_draw {
+            self.begin_stroke();
         }
     }
 
+    // Smallest multiple of the data grid spacing that spans at least one cell,
+    // so the cursor can never get stuck when the scale is coarse
+    fn data_grid_step(&self, scale: f64) -> f64 {
+        let mut step = self.data_snap_step.max(MIN_SCALE);
+        while step < scale.abs() {
+            step *= 2.0;
+        }
+        step
+    }
 
-    fn parse_hex_color(&self, hex: &str) -> Option<Color> {
-        if hex.len() != 6 {
-            return None;
+    // Tick spacing in data units: a data grid multiple at least 5 cells apart
+    fn tick_step(&self, scale: f64) -> f64 {
+        let mut step = self.data_grid_step(scale);
+        while step / scale.abs() < 5.0 {
+            step *= 2.0;
         }
-        
-        let r = u8::from_str_radix(&hex[0..2], 16).ok()?;
-        let g = u8::from_str_radix(&hex[2..4], 16).ok()?;
-        let b = u8::from_str_radix(&hex[4..6], 16).ok()?;
-        
-        Some(Color::Rgb(r, g, b))
+        step
     }
 
+    // Two-point calibration for tracing over imported plots: `:calibrate x,y`
+    // at one known point, then again at a second one diagonal from it; origin
+    // and scale are solved so both cells read the given values.
+    // `:calibrate` on its own cancels a pending first point.
+    fn calibrate(&mut self, arg: &str) {
+        if arg.is_empty() {
+            self.calibration_mark = None;
+            self.status_message = Some("Calibration cancelled".to_string());
+            return;
+        }
+        let values: Option<Vec<f64>> = arg.split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|part| !part.is_empty())
+            .map(|part| part.parse::<f64>().ok())
+            .collect();
+        let Some(&[data_x, data_y]) = values.as_deref() else {
+            self.status_message = Some("calibrate error: expected x,y".to_string());
+            return;
+        };
+        let (cell_x, cell_y) = (self.cursor_x, self.cursor_y);
+        let Some((ax, ay, adx, ady)) = self.calibration_mark.take() else {
+            self.calibration_mark = Some((cell_x, cell_y, data_x, data_y));
+            self.status_message = Some(format!("Marked ({}, {}); move to a second point and :calibrate x,y", data_x, data_y));
+            return;
+        };
+        match solve_calibration((ax, ay, adx, ady), (cell_x, cell_y, data_x, data_y), self.y_sign()) {
+            Ok(view) if !view_in_range(view) => {
+                self.status_message = Some(format!("calibrate error: scale must stay within {}..{} units per cell", MIN_SCALE, MAX_SCALE));
+            }
+            Ok((origin_x, origin_y, scale_x, scale_y)) => {
+                (self.origin_x, self.origin_y, self.scale_x, self.scale_y) = (origin_x, origin_y, scale_x, scale_y);
+                self.status_message = Some(format!("Calibrated: {:.4} x {:.4} units/cell", scale_x, scale_y));
+            }
+            Err(err) => self.status_message = Some(format!("calibrate error: {}", err)),
+        }
+    }
 
-    fn save_typst(&self) {
-        use std::env;
-        let filename = format!("{}/drawing.typ", env::current_dir().unwrap().display());
-        if let Ok(mut file) = File::create(&filename) {
-            let _ = writeln!(file, "#set page(margin: 0.5in, fill: black)");
-            let _ = writeln!(file, "#set text(size: 12pt, fill: rgb(\"#ff69b4\"))");
-            let _ = writeln!(file, "#set par(leading: 0.6em)");
-            let _ = writeln!(file, "");
-            let _ = writeln!(file, "= Mathematical Calculations");
-            let _ = writeln!(file, "");
-            
-            // Output natural Typst content
-            if !self.typst_content.is_empty() {
-                for line in &self.typst_content {
-                    if line.contains('$') {
-                        let _ = writeln!(file, "{}", line);
-                    } else if line.matches('=').count() == 1 && 
-                              (line.contains('+') || line.contains('-') || 
-                               line.contains('*') || line.contains('/')) {
-                        let _ = writeln!(file, "${}", line);
-                    } else {
-                        let _ = writeln!(file, "{}", line);
-                    }
-                }
-                let _ = writeln!(file, "");
+    // Graphing-calculator style window: map xmin..xmax across the canvas width
+    // and ymin..ymax over the visible rows by picking origin and scale
+    fn set_window(&mut self, arg: &str) {
+        let values: Option<Vec<f64>> = arg.split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|part| !part.is_empty())
+            .map(|part| part.parse::<f64>().ok())
+            .collect();
+        let (xmin, xmax, ymin, ymax) = match values.as_deref() {
+            Some(&[xmin, xmax, ymin, ymax]) if xmin < xmax && ymin < ymax => (xmin, xmax, ymin, ymax),
+            _ => {
+                self.status_message = Some("window error: expected xmin,xmax,ymin,ymax with min < max".to_string());
+                return;
             }
-            
-            // Add ASCII art drawing if present
-            let has_drawing = self.canvas.iter().any(|row| {
-                row.iter().any(|cell| matches!(cell, Some(DrawChar::Point | DrawChar::Horizontal | DrawChar::Vertical | DrawChar::Cross | DrawChar::DiagRight | DrawChar::DiagLeft)))
-            });
-            
-            if has_drawing {
-                // Pure ASCII art drawing
-                let _ = writeln!(file, "```");
-                for row in &self.canvas {
-                    let line: String = row.iter()
-                        .map(|cell| match cell {
-                            Some(DrawChar::Point) => '•',
-                            Some(DrawChar::Horizontal) => '-',
-                            Some(DrawChar::Vertical) => '|',
-                            Some(DrawChar::Cross) => '+',
-                            Some(DrawChar::DiagRight) => '/',
-                            Some(DrawChar::DiagLeft) => '\\',
-                            Some(DrawChar::Text(ch)) => *ch,
-                            None => ' ',
-                        })
-                        .collect();
-                    let _ = writeln!(file, "{}", line.trim_end());
-                }
-                let _ = writeln!(file, "```");
-            }
-            
+        };
+        let rows = self.canvas_height.min(self.virtual_height - self.scroll_y);
+        let scale_x = (xmax - xmin) / (self.canvas_width.max(2) - 1) as f64;
+        let scale_y = (ymax - ymin) / (rows.max(2) - 1) as f64;
+        // The top visible row holds ymax (or ymin when y grows downwards)
+        let top_value = if self.y_down { ymin } else { ymax };
+        let view = (-xmin / scale_x, self.scroll_y as f64 + top_value * self.y_sign() / scale_y, scale_x, scale_y);
+        if !view_in_range(view) {
+            self.status_message = Some(format!("window error: scale must stay within {}..{} units per cell", MIN_SCALE, MAX_SCALE));
+            return;
         }
-        
-        // Auto-compile to PDF if typst is available
-        self.compile_to_pdf();
+        (self.origin_x, self.origin_y, self.scale_x, self.scale_y) = view;
+        self.status_message = Some(format!("Window [{}, {}] x [{}, {}]: {:.4} x {:.4} units/cell", xmin, xmax, ymin, ymax, self.scale_x, self.scale_y));
     }
-    
-    fn compile_to_pdf(&self) {
-        use std::process::Command;
-        use std::env;
-        
-        let current_dir = env::current_dir().unwrap();
-        let typ_file = format!("{}/drawing.typ", current_dir.display());
+
+    // Data values offset + k * step that fall within [min, max]
+    fn grid_values(min: f64, max: f64, step: f64, offset: f64) -> Vec<f64> {
+        let (min, max) = (min.min(max), min.max(max));
+        let first = ((min - offset) / step).ceil() as i64;
+        let last = ((max - offset) / step).floor() as i64;
+        (first..=last).map(|k| offset + k as f64 * step).collect()
+    }
+
+    // Move from the nearest data grid intersection by one grid step in the
+    // direction of (dx, dy)
+    fn step_on_data_grid(&self, dx: f64, dy: f64) -> (f64, f64) {
+        let step_x = self.data_grid_step(self.scale_x);
+        let step_y = self.data_grid_step(self.scale_y);
+        let (data_x, data_y) = self.cell_to_data(self.cursor_x, self.cursor_y);
+
+        let mut target_x = ((data_x - self.grid_offset_x) / step_x).round() * step_x + self.grid_offset_x;
+        let mut target_y = ((data_y - self.grid_offset_y) / step_y).round() * step_y + self.grid_offset_y;
+        if dx != 0.0 {
+            target_x += dx.signum() * step_x;
+        }
+        if dy != 0.0 {
+            target_y -= dy.signum() * self.y_sign() * step_y; // Screen down is data down unless y_down
+        }
+        self.data_to_cell(target_x, target_y)
+    }
+
+    fn draw_line(&mut self, x0: f64, y0: f64, x1: f64, y1: f64) {
+        self.draw_line_colored(x0, y0, x1, y1, self.current_color, self.current_color);
+    }
+
+    // Lines placed with a line tool (polyline, ranges, rays) fade through the
+    // gradient when one is set
+    fn draw_tool_line(&mut self, x0: f64, y0: f64, x1: f64, y1: f64) {
+        let (start, end) = self.gradient.unwrap_or((self.current_color, self.current_color));
+        self.draw_line_colored(x0, y0, x1, y1, start, end);
+    }
+
+    // Continuous-draw strokes may use their own brush, see StrokeBrush
+    // `from` is where the local slope is measured from: the segment start, or
+    // further back along the stroke so hjkl staircases read as diagonals
+    fn draw_stroke_segment(&mut self, from: (f64, f64), x0: f64, y0: f64, x1: f64, y1: f64, color: Color) {
+        // Cells are taller than wide, so stretch rows to judge the slope
+        let slope_glyph = direction_glyph(x1 - from.0, (from.1 - y1) / self.cell_aspect);
+        let brush = match &self.stroke_brush {
+            StrokeBrush::Auto if self.current_char == DrawChar::Point => slope_glyph.unwrap_or_else(|| self.brush()),
+            StrokeBrush::Direction if !matches!(self.current_char, DrawChar::Text(_)) => {
+                slope_glyph.unwrap_or_else(|| self.brush())
+            }
+            StrokeBrush::Fixed(brush) => brush.clone(),
+            _ => self.brush(),
+        };
+        self.draw_line_brush(x0, y0, x1, y1, brush, (color, color));
+    }
+
+    // Color interpolated from `start` to `end` along the rasterized line
+    fn draw_line_colored(&mut self, x0: f64, y0: f64, x1: f64, y1: f64, start: Color, end: Color) {
+        self.draw_line_brush(x0, y0, x1, y1, self.brush(), (start, end));
+    }
+
+    fn draw_line_brush(&mut self, x0: f64, y0: f64, x1: f64, y1: f64, brush: DrawChar, (start, end): (Color, Color)) {
+        // With grid snap on, endpoints go to the nearest intersection the way
+        // the cursor does, rather than being truncated towards the origin
+        let (x0, y0, x1, y1) = if self.grid_snap {
+            (x0.round(), y0.round(), x1.round(), y1.round())
+        } else {
+            (x0, y0, x1, y1)
+        };
+        // Only the part on the canvas (plus the thick-line margin) is
+        // rasterized, so far-off endpoints can't overflow or allocate huge
+        // point lists; gradients keep the colors of the original ends
+        let (width, height) = (self.canvas_width as f64, self.virtual_height as f64);
+        let Some((t0, t1)) = clip_segment((x0, y0), (x1, y1), (-1.0, -1.0), (width, height)) else {
+            return;
+        };
+        let (start, end) = (lerp_color(start, end, t0), lerp_color(start, end, t1));
+        let (x0, y0, x1, y1) = (x0 + t0 * (x1 - x0), y0 + t0 * (y1 - y0), x0 + t1 * (x1 - x0), y0 + t1 * (y1 - y0));
+        // Thick lines stamp extra cells perpendicular to the main direction
+        let offsets: &[i32] = match self.line_width {
+            2 => &[0, 1],
+            3 => &[-1, 0, 1],
+            _ => &[0],
+        };
+        let mostly_horizontal = (x1 - x0).abs() >= (y1 - y0).abs();
+
+        let points = line_points(x0 as i32, y0 as i32, x1 as i32, y1 as i32);
+        let steps = points.len().saturating_sub(1).max(1) as f64;
+        for (i, (x, y)) in points.into_iter().enumerate() {
+            let color = lerp_color(start, end, i as f64 / steps);
+            for &offset in offsets {
+                let (x, y) = if mostly_horizontal { (x, y + offset) } else { (x + offset, y) };
+                if x >= 0 && y >= 0 {
+                    self.set_cell_colored(x as usize, y as usize, brush.clone(), color);
+                }
+            }
+        }
+    }
+
+    fn fill_polygon(&mut self, vertices: &[(f64, f64)], brush: DrawChar) {
+        let vertices: Vec<(f64, f64)> = vertices.iter().map(|&(x, y)| (x.floor(), y.floor())).collect();
+        for (x, y) in scanline_fill(&vertices, self.canvas_width as i32, self.virtual_height as i32) {
+            self.set_cell(x as usize, y as usize, brush.clone());
+        }
+    }
+
+    // Screen position of y = f(x) at every canvas column where it is defined
+    fn function_cells(&self, expr: &Expr) -> Vec<Option<(f64, f64)>> {
+        (0..self.canvas_width)
+            .map(|col| {
+                let (x, _) = self.cell_to_data(col as f64, 0.0);
+                let y = expr.eval(&[("x", x)]);
+                if y.is_finite() {
+                    let (_, row) = self.data_to_cell(x, y);
+                    Some((col as f64, row))
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    fn plot_function(&mut self, expr: &Expr) {
+        let limit = self.virtual_height as f64;
+        let mut previous: Option<(f64, f64)> = None;
+        for point in self.function_cells(expr) {
+            match (previous, point) {
+                // Don't join across asymptotes that leave the canvas entirely
+                (Some((x0, y0)), Some((x1, y1))) if y0.abs() < 2.0 * limit && y1.abs() < 2.0 * limit => {
+                    self.draw_line(x0, y0, x1, y1);
+                }
+                (_, Some((x, y))) if y >= 0.0 && y < limit => {
+                    self.set_cell(x as usize, y as usize, self.brush());
+                }
+                _ => {}
+            }
+            previous = point;
+        }
+    }
+
+    // r = f(theta) for theta over one full turn, in radians; negative r lands
+    // on the opposite side as usual
+    fn polar_cells(&self, expr: &Expr) -> Vec<Option<(f64, f64)>> {
+        const SAMPLES: usize = 1440;
+        (0..=SAMPLES)
+            .map(|i| {
+                let theta = i as f64 * std::f64::consts::TAU / SAMPLES as f64;
+                let r = expr.eval(&[("theta", theta)]);
+                r.is_finite().then(|| self.data_to_cell(r * theta.cos(), r * theta.sin()))
+            })
+            .collect()
+    }
+
+    fn plot_polar(&mut self, expr: &Expr) {
+        let (width, limit) = (self.canvas_width as f64, self.virtual_height as f64);
+        let mut previous: Option<(f64, f64)> = None;
+        for point in self.polar_cells(expr) {
+            match (previous, point) {
+                (Some((x0, y0)), Some((x1, y1)))
+                    if [y0, y1].iter().all(|y| y.abs() < 2.0 * limit) && [x0, x1].iter().all(|x| x.abs() < 2.0 * width) =>
+                {
+                    self.draw_line(x0, y0, x1, y1);
+                }
+                (_, Some((x, y))) if x >= 0.0 && y >= 0.0 && y < limit => {
+                    self.set_cell(x as usize, y as usize, self.brush());
+                }
+                _ => {}
+            }
+            previous = point;
+        }
+    }
+
+    fn curve_cells(&self, function: &PlottedFunction) -> Vec<Option<(f64, f64)>> {
+        if function.polar {
+            self.polar_cells(&function.expr)
+        } else {
+            self.function_cells(&function.expr)
+        }
+    }
+
+    // `:fn f(x)` (r = f(theta) in polar mode) adds a curve that is redrawn
+    // from its expression every frame, so it follows origin and scale
+    // changes. While editing from the manager it replaces that entry instead.
+    fn add_function(&mut self, arg: &str) {
+        let polar = matches!(self.coordinate_system, CoordinateSystem::Polar);
+        let expr = match Expr::parse(arg, if polar { &["theta"] } else { &["x"] }) {
+            Ok(expr) => expr,
+            Err(err) => {
+                self.function_editing = None;
+                self.status_message = Some(format!("Plot error: {}", err));
+                return;
+            }
+        };
+        let function = PlottedFunction {
+            source: arg.to_string(),
+            expr,
+            polar,
+            color: self.current_color,
+            brush: self.brush(),
+        };
+        let label = function.label();
+        match self.function_editing.take().filter(|&i| i < self.functions.len()) {
+            Some(i) => {
+                self.functions[i] = function;
+                self.function_selected = i;
+                self.mode = AppMode::Functions;
+            }
+            None => self.functions.push(function),
+        }
+        self.status_message = Some(format!("{} ({} functions, :fns to manage)", label, self.functions.len()));
+    }
+
+    // Draw every managed curve into the canvas in its own color and brush,
+    // as one undo step
+    fn bake_functions(&mut self) {
+        if self.functions.is_empty() {
+            return;
+        }
+        self.push_undo();
+        let (color, brush) = (self.current_color, self.current_char.clone());
+        for function in self.functions.clone() {
+            self.current_color = function.color;
+            self.current_char = function.brush.clone();
+            if function.polar {
+                self.plot_polar(&function.expr);
+            } else {
+                self.plot_function(&function.expr);
+            }
+        }
+        (self.current_color, self.current_char) = (color, brush);
+        self.status_message = Some(format!("Drew {} functions into the canvas", self.functions.len()));
+    }
+
+    fn handle_functions_keys(&mut self, key: KeyEvent) {
+        let count = self.functions.len();
+        match key.code {
+            KeyCode::Esc | KeyCode::Char('q') => self.mode = AppMode::Drawing,
+            KeyCode::Char('j') | KeyCode::Down if count > 0 => self.function_selected = (self.function_selected + 1) % count,
+            KeyCode::Char('k') | KeyCode::Up if count > 0 => self.function_selected = (self.function_selected + count - 1) % count,
+            KeyCode::Char('d') if count > 0 => {
+                self.functions.remove(self.function_selected);
+                self.function_selected = self.function_selected.min(count.saturating_sub(2));
+            }
+            // Take the current drawing color and brush
+            KeyCode::Char('c') if count > 0 => {
+                let brush = self.brush();
+                let function = &mut self.functions[self.function_selected];
+                function.color = self.current_color;
+                function.brush = brush;
+            }
+            KeyCode::Char('e') | KeyCode::Enter if count > 0 => {
+                self.function_editing = Some(self.function_selected);
+                self.command_input = format!("fn {}", self.functions[self.function_selected].source);
+                self.mode = AppMode::Command;
+            }
+            KeyCode::Char('b') => {
+                self.bake_functions();
+                self.mode = AppMode::Drawing;
+            }
+            _ => {}
+        }
+    }
+
+    // Exchange the per-tab fields on `App` with the parked tab at `index`
+    fn swap_tab_state(&mut self, index: usize) {
+        let tab = &mut self.tabs[index];
+        std::mem::swap(&mut self.canvas, &mut tab.canvas);
+        std::mem::swap(&mut self.cursor_x, &mut tab.cursor_x);
+        std::mem::swap(&mut self.cursor_y, &mut tab.cursor_y);
+        std::mem::swap(&mut self.origin_x, &mut tab.origin_x);
+        std::mem::swap(&mut self.origin_y, &mut tab.origin_y);
+        std::mem::swap(&mut self.scroll_y, &mut tab.scroll_y);
+        std::mem::swap(&mut self.typst_content, &mut tab.typst_content);
+        std::mem::swap(&mut self.undo_stack, &mut tab.undo_stack);
+        std::mem::swap(&mut self.save_path, &mut tab.save_path);
+        std::mem::swap(&mut self.annotations, &mut tab.annotations);
+        std::mem::swap(&mut self.heat_counts, &mut tab.heat_counts);
+        std::mem::swap(&mut self.functions, &mut tab.functions);
+        self.function_selected = 0;
+    }
+
+    // Add empty rows at the bottom of every tab, including undo snapshots so
+    // undoing never brings back a shorter canvas. Stops at MAX_CANVAS_ROWS.
+    fn grow_canvas(&mut self, rows: usize) {
+        let rows = rows.min(MAX_CANVAS_ROWS.saturating_sub(self.virtual_height));
+        if rows == 0 {
+            self.status_message = Some(format!("Canvas is already at the {} row limit", MAX_CANVAS_ROWS));
+            return;
+        }
+        self.virtual_height += rows;
+        let (height, width) = (self.virtual_height, self.canvas_width);
+        let grow = |canvas: &mut Vec<Vec<Option<CanvasCell>>>| canvas.resize(height, vec![None; width]);
+        grow(&mut self.canvas);
+        self.undo_stack.iter_mut().for_each(|snapshot| grow(&mut snapshot.canvas));
+        if let Some(base) = &mut self.stroke_base {
+            grow(base);
+        }
+        for tab in &mut self.tabs {
+            grow(&mut tab.canvas);
+            tab.undo_stack.iter_mut().for_each(|snapshot| grow(&mut snapshot.canvas));
+        }
+        self.canvas_version += 1;
+        self.status_message = Some(format!("Canvas is now {} rows", self.virtual_height));
+    }
+
+    fn switch_tab(&mut self, index: usize) {
+        if index == self.active_tab || index >= self.tabs.len() {
+            return;
+        }
+        self.swap_tab_state(self.active_tab);
+        self.swap_tab_state(index);
+        self.active_tab = index;
+        self.cursor_jumped();
+        self.polyline.clear();
+        self.canvas_version += 1;
+    }
+
+    fn close_tab(&mut self) {
+        if self.tabs.len() == 1 {
+            self.status_message = Some("Can't close the last tab".to_string());
+            return;
+        }
+        // The closed tab's state is on `App`; its stale slot can just go
+        self.tabs.remove(self.active_tab);
+        let next = self.active_tab.min(self.tabs.len() - 1);
+        self.swap_tab_state(next);
+        self.active_tab = next;
+        self.cursor_jumped();
+        self.polyline.clear();
+        self.canvas_version += 1;
+    }
+
+    // `:polygon sides radius [fill]`: radius in data units, first vertex
+    // straight up, centred on the cursor (on the nearest cell with grid snap)
+    fn draw_regular_polygon(&mut self, arg: &str) {
+        let parts: Vec<&str> = arg.split_whitespace().collect();
+        let (Some(Ok(sides)), Some(Ok(radius))) = (
+            parts.first().map(|p| p.parse::<usize>()),
+            parts.get(1).map(|p| p.parse::<f64>()),
+        ) else {
+            self.status_message = Some("polygon error: expected `polygon sides radius [fill]`".to_string());
+            return;
+        };
+        let fill = match parts.get(2) {
+            None => false,
+            Some(&"fill") => true,
+            Some(_) => {
+                self.status_message = Some("polygon error: the optional third word must be `fill`".to_string());
+                return;
+            }
+        };
+        if sides < 3 || !(radius > 0.0 && radius.is_finite()) {
+            self.status_message = Some("polygon error: needs at least 3 sides and a positive radius".to_string());
+            return;
+        }
+        if sides > MAX_POLYGON_SIDES {
+            self.status_message = Some(format!("polygon error: at most {} sides", MAX_POLYGON_SIDES));
+            return;
+        }
+
+        let (cx, cy) = if self.grid_snap {
+            (self.cursor_x.round(), self.cursor_y.round())
+        } else {
+            (self.cursor_x, self.cursor_y)
+        };
+        let (center_x, center_y) = self.cell_to_data(cx, cy);
+        let vertices: Vec<(f64, f64)> = (0..sides)
+            .map(|i| {
+                let angle = std::f64::consts::FRAC_PI_2 + i as f64 * std::f64::consts::TAU / sides as f64;
+                let (x, y) = self.data_to_cell(center_x + radius * angle.cos(), center_y + radius * angle.sin());
+                // Nearest cells rather than truncation keep the shape symmetric
+                (x.round(), y.round())
+            })
+            .collect();
+        // Edges are clipped while drawing, but a radius reaching this far
+        // past the canvas is a slip rather than a shape
+        let (width, height) = (self.canvas_width as f64, self.virtual_height as f64);
+        let far = |&(x, y): &(f64, f64)| !(x.abs() <= width * 10.0 + 1e3 && y.abs() <= height * 10.0 + 1e3);
+        if vertices.iter().any(far) {
+            self.status_message = Some(format!("polygon error: radius {} reaches far off the canvas", radius));
+            return;
+        }
+
+        self.push_undo();
+        for (i, &(x0, y0)) in vertices.iter().enumerate() {
+            let (x1, y1) = vertices[(i + 1) % sides];
+            self.draw_tool_line(x0, y0, x1, y1);
+        }
+        if fill {
+            self.fill_polygon(&vertices, self.brush());
+        }
+        self.status_message = Some(format!("Drew a {}-sided polygon of radius {}", sides, radius));
+    }
+
+    // Rotate everything about the data origin, counterclockwise in data
+    // coordinates. Each target cell takes the nearest source cell rotated
+    // back, so no holes open up; line glyphs turn with the drawing.
+    fn rotate_canvas(&mut self, degrees: f64) -> usize {
+        let (sin, cos) = degrees.to_radians().sin_cos();
+        let source = std::mem::replace(&mut self.canvas, vec![vec![None; self.canvas_width]; self.virtual_height]);
+        let mut moved = 0;
+        for y in 0..self.virtual_height {
+            for x in 0..self.canvas_width {
+                let (dx, dy) = self.cell_to_data(x as f64, y as f64);
+                let (sx, sy) = self.data_to_cell(dx * cos + dy * sin, dy * cos - dx * sin);
+                let (sx, sy) = (sx.round(), sy.round());
+                if sx < 0.0 || sy < 0.0 {
+                    continue;
+                }
+                let Some(Some(cell)) = source.get(sy as usize).and_then(|row| row.get(sx as usize)) else {
+                    continue;
+                };
+                let ch = match cell.ch {
+                    DrawChar::Horizontal | DrawChar::Vertical | DrawChar::DiagRight | DrawChar::DiagLeft => {
+                        let (gx, gy) = match cell.ch {
+                            DrawChar::Horizontal => (1.0, 0.0),
+                            DrawChar::Vertical => (0.0, 1.0),
+                            DrawChar::DiagRight => (1.0, 1.0),
+                            _ => (-1.0, 1.0),
+                        };
+                        // Glyphs are drawn on screen, where y_down mirrors the turn
+                        let (sin, cos) = (sin * self.y_sign(), cos);
+                        direction_glyph(gx * cos - gy * sin, gx * sin + gy * cos).unwrap_or(cell.ch.clone())
+                    }
+                    _ => cell.ch.clone(),
+                };
+                self.write_cell(x, y, Some(CanvasCell { ch, ..cell.clone() }));
+                moved += 1;
+            }
+        }
+        self.canvas_version += 1;
+        moved
+    }
+
+    // Draw from the cursor at `angle` degrees (counterclockwise from +x, as in
+    // polar input) to the canvas edge; `both_ways` extends it backwards too
+    fn draw_ray(&mut self, angle: f64, both_ways: bool) {
+        let (px, py) = (self.cursor_x, self.cursor_y);
+        let dx = angle.to_radians().cos() / self.scale_x;
+        let dy = -angle.to_radians().sin() / self.scale_y;
+
+        let (x1, y1) = self.edge_point(px, py, dx, dy);
+        let (x0, y0) = if both_ways { self.edge_point(px, py, -dx, -dy) } else { (px, py) };
+        self.draw_tool_line(x0, y0, x1, y1);
+    }
+
+    // Where the ray from (px, py) along (dx, dy) leaves the virtual canvas
+    fn edge_point(&self, px: f64, py: f64, dx: f64, dy: f64) -> (f64, f64) {
+        let max_x = self.canvas_width as f64 - 1.0;
+        let max_y = self.virtual_height as f64 - 1.0;
+        let mut t = f64::INFINITY;
+        if dx > 1e-12 {
+            t = t.min((max_x - px) / dx);
+        } else if dx < -1e-12 {
+            t = t.min(-px / dx);
+        }
+        if dy > 1e-12 {
+            t = t.min((max_y - py) / dy);
+        } else if dy < -1e-12 {
+            t = t.min(-py / dy);
+        }
+        if !t.is_finite() {
+            return (px, py);
+        }
+        ((px + t * dx).clamp(0.0, max_x), (py + t * dy).clamp(0.0, max_y))
+    }
+
+    // Place plain text on the canvas as text cells, one file line per row.
+    // Tabs expand to 8-column stops; long lines are clipped or wrapped.
+    // Returns the number of canvas rows used.
+    fn import_text(&mut self, contents: &str) -> usize {
+        let mut y = 0;
+        for line in contents.lines() {
+            let mut expanded = String::new();
+            for ch in line.chars() {
+                if ch == '\t' {
+                    let spaces = 8 - expanded.chars().count() % 8;
+                    expanded.extend(std::iter::repeat_n(' ', spaces));
+                } else {
+                    expanded.push(ch);
+                }
+            }
+
+            let chars: Vec<char> = expanded.chars().collect();
+            let segments: Vec<&[char]> = if self.import_wrap && !chars.is_empty() {
+                chars.chunks(self.canvas_width).collect()
+            } else {
+                vec![&chars[..chars.len().min(self.canvas_width)]]
+            };
+            for segment in segments {
+                if y >= self.virtual_height {
+                    return y;
+                }
+                for (x, &ch) in segment.iter().enumerate() {
+                    if !ch.is_whitespace() {
+                        self.set_cell(x, y, DrawChar::Text(ch));
+                    }
+                }
+                y += 1;
+            }
+        }
+        y
+    }
+
+    // `:stamp name w h` captures the w x h region whose top-left is the cursor
+    fn define_stamp(&mut self, arg: &str) {
+        let parts: Vec<&str> = arg.split_whitespace().collect();
+        let (Some(name), Some(Ok(width)), Some(Ok(height))) = (
+            parts.first(),
+            parts.get(1).map(|p| p.parse::<usize>()),
+            parts.get(2).map(|p| p.parse::<usize>()),
+        ) else {
+            self.status_message = Some("Stamp error: expected `stamp name w h`".to_string());
+            return;
+        };
+
+        // Only the part of the region that is on the canvas
+        let (left, top) = (self.cursor_x as usize, self.cursor_y as usize);
+        let width = width.min(self.canvas_width.saturating_sub(left));
+        let height = height.min(self.virtual_height.saturating_sub(top));
+        if width == 0 || height == 0 {
+            self.status_message = Some("Stamp error: the region is empty".to_string());
+            return;
+        }
+        let stamp: Vec<Vec<Option<CanvasCell>>> = self.canvas[top..top + height]
+            .iter()
+            .map(|row| row[left..left + width].to_vec())
+            .collect();
+        self.stamps.insert(name.to_string(), stamp);
+        self.status_message = Some(format!("Stamp '{}' defined ({}x{})", name, width, height));
+    }
+
+    // `:export w h file` writes just the w x h region whose top-left is the
+    // cursor, in the format given by the file extension
+    fn export_region(&mut self, arg: &str) {
+        let parts: Vec<&str> = arg.split_whitespace().collect();
+        let (Some(Ok(width)), Some(Ok(height)), Some(&path)) = (
+            parts.first().map(|p| p.parse::<usize>()),
+            parts.get(1).map(|p| p.parse::<usize>()),
+            parts.get(2),
+        ) else {
+            self.status_message = Some("export error: expected `export w h file`".to_string());
+            return;
+        };
+        // Only the part of the region that is on the canvas
+        let (left, top) = (self.cursor_x as usize, self.cursor_y as usize);
+        let width = width.min(self.canvas_width.saturating_sub(left));
+        let height = height.min(self.virtual_height.saturating_sub(top));
+        if width == 0 || height == 0 {
+            self.status_message = Some("export error: the region is empty".to_string());
+            return;
+        }
+        let extension = Path::new(path).extension().and_then(|e| e.to_str()).unwrap_or("");
+        match extension {
+            "typ" => self.with_region(left, top, width, height, |app| {
+                let previous = std::mem::replace(&mut app.save_path, path.to_string());
+                app.save_typst();
+                app.save_path = previous;
+            }),
+            "tex" | "txt" => {
+                let contents = self.with_region(left, top, width, height, |app| {
+                    if extension == "tex" { app.to_tikz() } else { app.to_ascii() }
+                });
+                self.status_message = Some(match std::fs::write(path, contents) {
+                    Ok(()) => format!("Saved {}x{} region to {}", width, height, path),
+                    Err(_) => format!("Could not write {}", path),
+                });
+            }
+            _ => self.status_message = Some("export error: file must end in .typ, .tex or .txt".to_string()),
+        }
+    }
+
+    // Run `f` with the canvas cropped to a region moved to the top-left
+    // corner, the origin moving with it so data coordinates are unchanged.
+    // The region is cut down to the part that overlaps the canvas.
+    fn with_region<T>(&mut self, left: usize, top: usize, width: usize, height: usize, f: impl FnOnce(&mut Self) -> T) -> T {
+        let bottom = top.saturating_add(height).min(self.canvas.len());
+        let cropped: Vec<Vec<Option<CanvasCell>>> = self.canvas[top.min(bottom)..bottom]
+            .iter()
+            .map(|row| {
+                let right = left.saturating_add(width).min(row.len());
+                row[left.min(right)..right].to_vec()
+            })
+            .collect();
+        let full = std::mem::replace(&mut self.canvas, cropped);
+        self.origin_x -= left as f64;
+        self.origin_y -= top as f64;
+        let result = f(self);
+        self.origin_x += left as f64;
+        self.origin_y += top as f64;
+        self.canvas = full;
+        result
+    }
+
+    // `:place name` stamps a saved region with its top-left at the cursor
+    fn place_stamp(&mut self, name: &str) {
+        let Some(stamp) = self.stamps.get(name).cloned() else {
+            self.status_message = Some(format!("No stamp named '{}'", name));
+            return;
+        };
+
+        self.push_undo();
+        let (left, top) = (self.cursor_x as usize, self.cursor_y as usize);
+        for (dy, row) in stamp.into_iter().enumerate() {
+            for (dx, cell) in row.into_iter().enumerate() {
+                let (x, y) = (left + dx, top + dy);
+                if let Some(cell) = cell {
+                    self.put_cell(x, y, cell);
+                }
+            }
+        }
+    }
+
+    // `:table rows cols [width [height]]` rules a grid with its top-left corner
+    // at the cursor; cells default to 4 columns by 1 row of room for text
+    fn stamp_table(&mut self, arg: &str) {
+        let sizes: std::result::Result<Vec<usize>, _> = arg.split_whitespace().map(str::parse).collect();
+        let (rows, cols, width, height) = match sizes.as_deref() {
+            Ok([rows, cols]) => (*rows, *cols, 4, 1),
+            Ok([rows, cols, width]) => (*rows, *cols, *width, 1),
+            Ok([rows, cols, width, height]) => (*rows, *cols, *width, *height),
+            _ => (0, 0, 0, 0),
+        };
+        if rows == 0 || cols == 0 || width == 0 || height == 0 {
+            self.status_message = Some("table error: expected `table rows cols [width [height]]`".to_string());
+            return;
+        }
+
+        // A cell bigger than the canvas rules the same cells as one that
+        // just fits, and keeps the spacing arithmetic below from overflowing
+        let (width, height) = (width.min(self.canvas_width), height.min(self.virtual_height));
+        let (Some(total_height), Some(total_width)) = (
+            (height + 1).checked_mul(rows),
+            (width + 1).checked_mul(cols),
+        ) else {
+            self.status_message = Some("table error: table is too large".to_string());
+            return;
+        };
+
+        self.push_undo();
+        let (left, top) = (self.cursor_x as usize, self.cursor_y as usize);
+        // Only the part of the table that lands on the canvas is ruled
+        let last_row = total_height.min(self.virtual_height.saturating_sub(top + 1));
+        let last_col = total_width.min(self.canvas_width.saturating_sub(left + 1));
+        for dy in 0..=last_row {
+            for dx in 0..=last_col {
+                let on_row = dy % (height + 1) == 0;
+                let on_col = dx % (width + 1) == 0;
+                let ch = match (on_row, on_col) {
+                    (true, true) => DrawChar::Cross,
+                    (true, false) => DrawChar::Horizontal,
+                    (false, true) => DrawChar::Vertical,
+                    (false, false) => continue,
+                };
+                self.set_cell(left + dx, top + dy, ch);
+            }
+        }
+        self.status_message = Some(format!("Table {}x{} placed", rows, cols));
+    }
+
+    // `:field dx ; dy [; spacing]`: an arrow every `spacing` columns (half as many
+    // rows, cells being tall) pointing along (dx, dy) evaluated at that point
+    fn plot_vector_field(&mut self, arg: &str) {
+        let parts: Vec<&str> = arg.split(';').collect();
+        let spacing = match parts.get(2).map(|part| part.trim().parse::<usize>()) {
+            None => 4,
+            Some(Ok(spacing)) if spacing > 0 => spacing,
+            Some(_) => {
+                self.status_message = Some("Field error: spacing must be a positive whole number".to_string());
+                return;
+            }
+        };
+        let parsed = match parts[..] {
+            [dx, dy] | [dx, dy, _] => Expr::parse(dx, &["x", "y"]).and_then(|dx| Ok((dx, Expr::parse(dy, &["x", "y"])?))),
+            _ => Err("expected dx ; dy [; spacing]".to_string()),
+        };
+        let (dx, dy) = match parsed {
+            Ok(exprs) => exprs,
+            Err(err) => {
+                self.status_message = Some(format!("Field error: {}", err));
+                return;
+            }
+        };
+
+        self.push_undo();
+        let rows = self.canvas_height.min(self.virtual_height - self.scroll_y);
+        let row_spacing = (spacing / 2).max(1);
+        let mut arrows = 0;
+        for r in (row_spacing / 2..rows).step_by(row_spacing) {
+            for c in (spacing / 2..self.canvas_width).step_by(spacing) {
+                let row = self.scroll_y + r;
+                let (x, y) = self.cell_to_data(c as f64, row as f64);
+                let (vx, vy) = (dx.eval(&[("x", x), ("y", y)]), dy.eval(&[("x", x), ("y", y)]));
+                if !vx.is_finite() || !vy.is_finite() {
+                    continue;
+                }
+                // Direction as it appears on screen: cells are narrower than tall
+                let screen_x = vx / self.scale_x * self.cell_aspect;
+                let screen_up = vy * self.y_sign() / self.scale_y;
+                self.set_cell(c, row, DrawChar::Text(arrow_glyph(screen_x, screen_up)));
+                arrows += 1;
+            }
+        }
+        self.status_message = Some(format!("Vector field: {} arrows", arrows));
+    }
+
+    // `:implicit lhs = rhs` marks visible cells where lhs - rhs changes sign
+    fn plot_implicit(&mut self, arg: &str) {
+        let parsed = match arg.split_once('=') {
+            Some((lhs, rhs)) => Expr::parse(lhs, &["x", "y"]).and_then(|lhs| {
+                Ok(Expr::Binary('-', Box::new(lhs), Box::new(Expr::parse(rhs, &["x", "y"])?)))
+            }),
+            None => Expr::parse(arg, &["x", "y"]),
+        };
+        let expr = match parsed {
+            Ok(expr) => expr,
+            Err(err) => {
+                self.status_message = Some(format!("Implicit plot error: {}", err));
+                return;
+            }
+        };
+
+        // Sample f on the cell corners of the visible window
+        let rows = self.canvas_height.min(self.virtual_height - self.scroll_y);
+        let values: Vec<Vec<f64>> = (0..=rows)
+            .map(|r| {
+                (0..=self.canvas_width)
+                    .map(|c| {
+                        let (x, y) = self.cell_to_data(c as f64 - 0.5, (self.scroll_y + r) as f64 - 0.5);
+                        expr.eval(&[("x", x), ("y", y)])
+                    })
+                    .collect()
+            })
+            .collect();
+
+        self.push_undo();
+        let mut marked = 0;
+        for r in 0..rows {
+            for c in 0..self.canvas_width {
+                let corners = [values[r][c], values[r][c + 1], values[r + 1][c], values[r + 1][c + 1]];
+                if corners.iter().any(|v| !v.is_finite()) {
+                    continue;
+                }
+                let has_neg = corners.iter().any(|&v| v < 0.0);
+                let has_pos = corners.iter().any(|&v| v > 0.0);
+                let has_zero = corners.contains(&0.0);
+                if (has_neg && has_pos) || has_zero {
+                    self.set_cell(c, self.scroll_y + r, self.brush());
+                    marked += 1;
+                }
+            }
+        }
+        self.status_message = Some(format!("Implicit plot: {} cells on {}", marked, arg.trim()));
+    }
+
+    // `:shade a b [char]` fills between the last plot and the x-axis
+    fn shade_under_plot(&mut self, arg: &str) {
+        let Some(expr) = self.last_plot.clone() else {
+            self.status_message = Some("Shade error: plot a function first (y)".to_string());
+            return;
+        };
+        let parts: Vec<&str> = arg.split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|part| !part.is_empty())
+            .collect();
+        let (Some(Ok(a)), Some(Ok(b))) = (parts.first().map(|p| p.parse::<f64>()), parts.get(1).map(|p| p.parse::<f64>())) else {
+            self.status_message = Some("Shade error: expected `shade a b [char]`".to_string());
+            return;
+        };
+        if !a.is_finite() || !b.is_finite() {
+            self.status_message = Some("Shade error: bounds must be finite numbers".to_string());
+            return;
+        }
+        let (a, b) = (a.min(b), a.max(b));
+        let brush = parts.get(2)
+            .and_then(|p| p.chars().next())
+            .and_then(DrawChar::from_glyph)
+            .unwrap_or(DrawChar::Vertical);
+
+        // Polygon along the curve from a to b, closed along the x-axis. The
+        // bounds are clamped to the canvas columns so far-off ones can't spin.
+        let last_col = self.canvas_width as f64 - 1.0;
+        let (start_col, _) = self.data_to_cell(a, 0.0);
+        let (end_col, _) = self.data_to_cell(b, 0.0);
+        let (start_col, end_col) = (start_col.min(end_col), start_col.max(end_col));
+        if end_col < 0.0 || start_col > last_col {
+            self.status_message = Some(format!("Shade error: x ∈ [{}, {}] is off the canvas", a, b));
+            return;
+        }
+        let (start_col, end_col) = (start_col.max(0.0), end_col.min(last_col));
+        let mut vertices = vec![(start_col, self.origin_y)];
+        let mut col = start_col;
+        while col <= end_col {
+            let (x, _) = self.cell_to_data(col, 0.0);
+            let y = expr.eval(&[("x", x)]);
+            if y.is_finite() {
+                // Rows far past the canvas only need to stay far past it; this
+                // keeps the fill's edge arithmetic from overflowing
+                let (cx, cy) = self.data_to_cell(x, y);
+                vertices.push((cx, cy.clamp(-1e6, 1e6)));
+            }
+            col += 1.0;
+        }
+        vertices.push((end_col, self.origin_y));
+
+        self.push_undo();
+        self.fill_polygon(&vertices, brush);
+        self.status_message = Some(format!("Shaded x ∈ [{}, {}]", a, b));
+    }
+
+    fn push_undo(&mut self) {
+        if self.undo_stack.len() == UNDO_LIMIT {
+            self.undo_stack.remove(0);
+        }
+        self.undo_stack.push(UndoSnapshot {
+            canvas: self.canvas.clone(),
+            typst_content: self.typst_content.clone(),
+            heat_counts: self.heat_counts.clone(),
+        });
+    }
+
+    // Animate the undo history from oldest snapshot to the current canvas
+    fn start_replay(&mut self, delay_ms: u128) {
+        let mut frames: Vec<Vec<Vec<Option<CanvasCell>>>> = Vec::new();
+        for snapshot in self.undo_stack.iter().map(|snapshot| &snapshot.canvas).chain(std::iter::once(&self.canvas)) {
+            if frames.last() != Some(snapshot) {
+                frames.push(snapshot.clone());
+            }
+        }
+        if frames.len() < 2 {
+            self.status_message = Some("Nothing to replay".to_string());
+            return;
+        }
+        self.replay = Some(Replay {
+            frames,
+            next_frame: 0,
+            delay_ms,
+            last_step: Instant::now(),
+            final_canvas: self.canvas.clone(),
+        });
+        self.advance_replay();
+    }
+
+    // Show the next replay frame once its delay has passed; true if the canvas changed
+    fn advance_replay(&mut self) -> bool {
+        let Some(replay) = &mut self.replay else {
+            return false;
+        };
+        if replay.next_frame > 0 && replay.last_step.elapsed().as_millis() < replay.delay_ms {
+            return false;
+        }
+        match replay.frames.get(replay.next_frame) {
+            Some(frame) => {
+                self.canvas = frame.clone();
+                replay.next_frame += 1;
+                replay.last_step = Instant::now();
+                self.canvas_version += 1;
+                self.status_message = Some(format!("Replay {}/{} | Esc to stop", replay.next_frame, replay.frames.len()));
+            }
+            None => self.stop_replay(),
+        }
+        true
+    }
+
+    fn stop_replay(&mut self) {
+        if let Some(replay) = self.replay.take() {
+            self.canvas = replay.final_canvas;
+            self.canvas_version += 1;
+            self.status_message = Some("Replay finished".to_string());
+        }
+    }
+
+    fn undo(&mut self) {
+        match self.undo_stack.pop() {
+            Some(snapshot) => {
+                self.canvas = snapshot.canvas;
+                self.typst_content = snapshot.typst_content;
+                self.heat_counts = snapshot.heat_counts;
+                self.canvas_version += 1;
+            }
+            None => self.status_message = Some("Nothing to undo".to_string()),
+        }
+    }
+
+    fn draw_char(&mut self) {
+        let x = self.cursor_x as usize;
+        let y = self.cursor_y as usize;
+        self.set_cell(x, y, self.brush());
+    }
+
+    // Write a glyph with the current color (and text attributes for text);
+    // false if the cell is off-canvas or kept by merge mode
+    fn set_cell(&mut self, x: usize, y: usize, ch: DrawChar) -> bool {
+        self.set_cell_colored(x, y, ch, self.current_color)
+    }
+
+    fn set_cell_colored(&mut self, x: usize, y: usize, ch: DrawChar, color: Color) -> bool {
+        let attrs = match ch {
+            DrawChar::Text(_) => self.text_attrs,
+            _ => TextAttrs::default(),
+        };
+        self.put_cell(x, y, CanvasCell { ch, color, attrs })
+    }
+
+    // Central write path for every drawing operation. In merge mode occupied
+    // cells are left untouched. Bounds come from the canvas itself, so no
+    // cursor state can make this index out of range.
+    fn put_cell(&mut self, x: usize, y: usize, cell: CanvasCell) -> bool {
+        let occupied = self.canvas.get(y).and_then(|row| row.get(x)).is_some_and(Option::is_some);
+        if self.merge_draw && occupied {
+            return false;
+        }
+        self.write_cell(x, y, Some(cell))
+    }
+
+    // put_cell without merge mode, for whole-canvas edits (rotation, heatmap
+    // shading, row copies) whose result must not depend on what was there
+    fn write_cell(&mut self, x: usize, y: usize, cell: Option<CanvasCell>) -> bool {
+        match self.canvas.get_mut(y).and_then(|row| row.get_mut(x)) {
+            Some(slot) => {
+                *slot = cell;
+                self.mark_dirty(y);
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn mark_dirty(&mut self, y: usize) {
+        self.dirty_rows = Some(match self.dirty_rows {
+            Some((first, last)) => (first.min(y), last.max(y)),
+            None => (y, y),
+        });
+        self.dirty_writes += 1;
+        self.canvas_version += 1;
+    }
+
+    fn clear_cell(&mut self, x: usize, y: usize) -> bool {
+        self.write_cell(x, y, None)
+    }
+
+    // Rebuild the styled spans for the visible rows, but only when the canvas
+    // or the scroll position changed since the last frame. When every change
+    // since then came through write_cell, only the dirty rows are redone.
+    fn refresh_render_cache(&mut self) {
+        let key = (self.canvas_version, self.scroll_y);
+        let dirty = self.dirty_rows.take();
+        let writes = std::mem::take(&mut self.dirty_writes);
+        if self.render_cache_key == Some(key) {
+            return;
+        }
+
+        let rows = self.scroll_y..(self.scroll_y + self.canvas_height).min(self.canvas.len());
+        match (self.render_cache_key, dirty) {
+            (Some((version, scroll)), Some((first, last)))
+                if scroll == self.scroll_y && version + writes == self.canvas_version && self.render_cache.len() == rows.len() =>
+            {
+                for y in first.max(rows.start)..=last.min(rows.end.saturating_sub(1)) {
+                    self.render_cache[y - rows.start] = Self::render_row(&self.canvas[y]);
+                }
+            }
+            _ => self.render_cache = self.canvas[rows].iter().map(|row| Self::render_row(row)).collect(),
+        }
+        self.render_cache_key = Some(key);
+    }
+
+    fn render_row(row: &[Option<CanvasCell>]) -> Vec<(usize, Span<'static>)> {
+        row.iter()
+            .enumerate()
+            .filter_map(|(x, cell)| {
+                cell.as_ref().map(|cell| {
+                    let style = Style::default().fg(cell.color).add_modifier(cell.attrs.modifier());
+                    (x, Span::styled(cell.ch.glyph().to_string(), style))
+                })
+            })
+            .collect()
+    }
+
+
+    // Run a line-based drawing script; coordinates are data coordinates except
+    // for `origin`, which places the origin at a canvas cell
+    //   point x y | line x1 y1 x2 y2 | text x y "..." | color #rgb | origin col row
+    fn run_script(&mut self, script: &str) -> std::result::Result<(), String> {
+        for (number, line) in script.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with("//") {
+                continue;
+            }
+            self.run_script_line(line).map_err(|err| format!("line {}: {}", number + 1, err))?;
+        }
+        Ok(())
+    }
+
+    fn run_script_line(&mut self, line: &str) -> std::result::Result<(), String> {
+        let (command, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+        let numbers = |text: &str, count: usize| -> std::result::Result<Vec<f64>, String> {
+            let values: Vec<f64> = text.split_whitespace()
+                .map(|part| part.parse::<f64>().ok().filter(|v| v.is_finite()).ok_or(format!("'{}' is not a number", part)))
+                .collect::<std::result::Result<_, _>>()?;
+            if values.len() != count {
+                return Err(format!("{} expects {} numbers, got {}", command, count, values.len()));
+            }
+            Ok(values)
+        };
+        match command {
+            "point" => {
+                let v = numbers(rest, 2)?;
+                let (x, y) = self.data_to_cell(v[0], v[1]);
+                if !(x >= 0.0 && y >= 0.0 && x < self.canvas_width as f64 && y < self.virtual_height as f64) {
+                    return Err("point lies off the canvas".to_string());
+                }
+                self.set_cell(x as usize, y as usize, DrawChar::Point);
+            }
+            "line" => {
+                let v = numbers(rest, 4)?;
+                let (x0, y0) = self.data_to_cell(v[0], v[1]);
+                let (x1, y1) = self.data_to_cell(v[2], v[3]);
+                // Partly visible lines are clipped while drawing; one that
+                // misses the canvas entirely is most likely a typo
+                let size = (self.canvas_width as f64, self.virtual_height as f64);
+                if clip_segment((x0, y0), (x1, y1), (0.0, 0.0), size).is_none() {
+                    return Err("line lies off the canvas".to_string());
+                }
+                self.draw_line(x0, y0, x1, y1);
+            }
+            "text" => {
+                let (position, quoted) = rest.split_once('"').ok_or("text expects x y \"...\"")?;
+                let text = quoted.strip_suffix('"').ok_or("unterminated text string")?;
+                let v = numbers(position, 2)?;
+                let (x, y) = self.data_to_cell(v[0], v[1]);
+                // Like lines, text running off an edge is clipped, but text
+                // with no character on the canvas is an error
+                let end = x.floor() + text.chars().count() as f64;
+                if !(y >= 0.0 && y < self.virtual_height as f64 && end > 0.0 && x < self.canvas_width as f64) {
+                    return Err("text lies off the canvas".to_string());
+                }
+                for (i, ch) in text.chars().enumerate() {
+                    let col = x.floor() as i64 + i as i64;
+                    if col >= 0 {
+                        self.set_cell(col as usize, y as usize, DrawChar::Text(ch));
+                    }
+                }
+            }
+            "color" => {
+                let hex = rest.trim().trim_start_matches('#');
+                if !hex.chars().all(|c| c.is_ascii_hexdigit()) {
+                    return Err(format!("invalid color '{}'", rest.trim()));
+                }
+                // Short #rgb form doubles each digit
+                let hex = if hex.len() == 3 {
+                    hex.chars().flat_map(|c| [c, c]).collect()
+                } else {
+                    hex.to_string()
+                };
+                self.current_color = self.parse_hex_color(&hex).ok_or(format!("invalid color '{}'", rest.trim()))?;
+            }
+            "origin" => {
+                let v = numbers(rest, 2)?;
+                self.origin_x = v[0];
+                self.origin_y = v[1];
+            }
+            _ => return Err(format!("unknown command '{}'", command)),
+        }
+        Ok(())
+    }
+
+    fn parse_hex_color(&self, hex: &str) -> Option<Color> {
+        if hex.len() != 6 {
+            return None;
+        }
         
-        // Try to compile with typst
-        match Command::new("typst")
-            .args(["compile", &typ_file])
-            .output() {
-            Ok(output) => {
-                if output.status.success() {
-                    // Success - PDF created
+        let r = u8::from_str_radix(&hex[0..2], 16).ok()?;
+        let g = u8::from_str_radix(&hex[2..4], 16).ok()?;
+        let b = u8::from_str_radix(&hex[4..6], 16).ok()?;
+        
+        Some(Color::Rgb(r, g, b))
+    }
+
+
+    // The typst document `s` writes: page setup (or a #figure wrapper),
+    // typed text, then the drawing as a raw block or tinted runs
+    fn to_typst(&self) -> String {
+        use std::fmt::Write as _;
+        let mut out = String::new();
+        if self.figure_export {
+            // Self-contained figure for `#include` in a larger document
+            let _ = write!(out, "{}", self.stamps_to_comments());
+            let _ = writeln!(out, "#figure(");
+            let _ = writeln!(out, "  rect(stroke: 0.5pt, inset: 8pt)[");
+        } else {
+            match self.fit_page {
+                Some(paper) => {
+                    // Shrink the text until the widest drawing row fits the page
+                    let size = self.fit_text_size(paper);
+                    let _ = writeln!(out, "#set page(paper: \"{}\", margin: 0.5in, fill: black)", paper.typst_name());
+                    let _ = writeln!(out, "#set text(size: {:.1}pt, fill: rgb(\"#ff69b4\"))", size);
+                    let _ = writeln!(out, "#show raw: set text(size: {:.1}pt)", size);
+                }
+                None => {
+                    let _ = writeln!(out, "#set page(margin: 0.5in, fill: black)");
+                    let _ = writeln!(out, "#set text(size: 12pt, fill: rgb(\"#ff69b4\"))");
+                }
+            }
+            let _ = writeln!(out, "#set par(leading: 0.6em)");
+            let _ = writeln!(out);
+            let _ = write!(out, "{}", self.stamps_to_comments());
+            let _ = writeln!(out, "= Mathematical Calculations");
+            let _ = writeln!(out);
+        }
+        
+        // Output natural Typst content
+        if !self.typst_content.is_empty() {
+            for TypstLine { text: line, attrs } in &self.typst_content {
+                let line = if self.auto_math { typst_markup(line) } else { line.clone() };
+                let _ = writeln!(out, "{}", attrs.wrap_typst(&line));
+            }
+            let _ = writeln!(out);
+        }
+        
+        // Add ASCII art drawing if present
+        let has_drawing = self.has_drawing();
+        let single_color = self.canvas.iter().flatten().flatten().all(|cell| cell.color == DEFAULT_COLOR);
+
+        let empty_cell = self.export_empty_cell();
+        if has_drawing && empty_cell != ' ' {
+            // Lets load_drawing tell placeholders from drawn cells
+            let _ = writeln!(out, "// dravi-empty: {}", empty_cell);
+        }
+        if has_drawing && single_color {
+            // Pure ASCII art drawing
+            let _ = writeln!(out, "```");
+            let _ = write!(out, "{}", self.to_ascii());
+            let _ = writeln!(out, "```");
+        } else if has_drawing {
+            // Same layout, but with each run of same-colored cells tinted
+            let _ = write!(out, "{}", self.to_colored_typst());
+        }
+
+        if self.figure_export {
+            let _ = writeln!(out, "  ],");
+            let _ = writeln!(out, "  caption: [{}],", typst_escape(&self.figure_caption));
+            let _ = writeln!(out, ")");
+        }
+
+        out
+    }
+
+    // Typed text alone goes out as typst paragraphs; any drawn glyph makes
+    // the canvas a drawing that is exported as a block. Heatmap shades are
+    // stored as text but are drawing all the same.
+    fn has_drawing(&self) -> bool {
+        self.canvas
+            .iter()
+            .flatten()
+            .flatten()
+            .any(|cell| !matches!(cell.ch, DrawChar::Text(ch) if !HEAT_GLYPHS.contains(&ch)))
+    }
+
+    fn save_typst(&mut self) {
+        use std::env;
+        let filename = env::current_dir().unwrap().join(&self.save_path);
+        self.status_message = Some(match std::fs::write(&filename, self.to_typst()) {
+            Ok(()) => format!("Saved to {}", self.save_path),
+            Err(_) => format!("Could not write {}", self.save_path),
+        });
+        
+        // Auto-compile to PDF if typst is available
+        self.compile_to_pdf();
+    }
+    
+    // Save a copy stamped with the current time, e.g. drawing-20240101-120000.typ,
+    // leaving save_path (and the file `s` writes) untouched
+    fn save_snapshot(&mut self) {
+        let path = snapshot_path(&self.save_path, SystemTime::now());
+        let previous = std::mem::replace(&mut self.save_path, path);
+        self.save_typst();
+        self.save_path = previous;
+    }
+
+    // Export next to the typst file, e.g. drawing.typ -> drawing.tex
+    fn save_tikz(&mut self) {
+        let path = std::path::Path::new(&self.save_path).with_extension("tex");
+        self.status_message = Some(match std::fs::write(&path, self.to_tikz()) {
+            Ok(()) => format!("Saved to {}", path.display()),
+            Err(_) => format!("Could not write {}", path.display()),
+        });
+    }
+
+    // A tikzpicture in data coordinates: straight glyph runs become \draw
+    // segments, points \fill dots, everything else \node text
+    fn to_tikz(&self) -> String {
+        let mut out = String::from("% needs \\usepackage{tikz}\n");
+        let mut colors: Vec<Color> = Vec::new();
+        for cell in self.canvas.iter().flatten().flatten() {
+            if !colors.contains(&cell.color) {
+                colors.push(cell.color);
+            }
+        }
+        for (i, color) in colors.iter().enumerate() {
+            let (r, g, b) = match *color {
+                Color::Rgb(r, g, b) => (r, g, b),
+                _ => (0, 0, 0),
+            };
+            out.push_str(&format!("\\definecolor{{dravi{}}}{{RGB}}{{{},{},{}}}\n", i, r, g, b));
+        }
+        // Keep each cell's on-screen proportions so circles stay round
+        out.push_str(&format!(
+            "\\begin{{tikzpicture}}[x={:.4}cm, y={:.4}cm]\n",
+            0.2 / self.scale_x.abs(),
+            0.2 / self.cell_aspect / self.scale_y.abs() * self.y_sign(),
+        ));
+
+        let point = |x: f64, y: f64| {
+            let (dx, dy) = self.cell_to_data(x, y);
+            format!("({:.3}, {:.3})", dx + 0.0, dy + 0.0)
+        };
+        let color_name = |color: Color| format!("dravi{}", colors.iter().position(|&c| c == color).unwrap_or(0));
+        let cell_at = |x: i64, y: i64| -> Option<&CanvasCell> {
+            if x < 0 || y < 0 {
+                return None;
+            }
+            self.canvas.get(y as usize)?.get(x as usize)?.as_ref()
+        };
+
+        if self.show_axes {
+            let mut bounds = (self.origin_x, self.origin_y, self.origin_x, self.origin_y);
+            for (y, row) in self.canvas.iter().enumerate() {
+                for (x, cell) in row.iter().enumerate() {
+                    if cell.is_some() {
+                        let (x, y) = (x as f64, y as f64);
+                        bounds = (bounds.0.min(x), bounds.1.min(y), bounds.2.max(x), bounds.3.max(y));
+                    }
+                }
+            }
+            let (x0, y0, x1, y1) = (bounds.0 - 1.0, bounds.1 - 1.0, bounds.2 + 1.0, bounds.3 + 1.0);
+            out.push_str(&format!("  \\draw[->] {} -- {};\n", point(x0, self.origin_y), point(x1, self.origin_y)));
+            out.push_str(&format!("  \\draw[->] {} -- {};\n", point(self.origin_x, y1), point(self.origin_x, y0)));
+        }
+
+        for (y, row) in self.canvas.iter().enumerate() {
+            let mut x = 0;
+            while x < row.len() {
+                let Some(cell) = &row[x] else {
+                    x += 1;
+                    continue;
+                };
+                let (xi, yi) = (x as i64, y as i64);
+                let direction = match cell.ch {
+                    DrawChar::Horizontal => Some((1, 0)),
+                    DrawChar::Vertical => Some((0, 1)),
+                    DrawChar::DiagRight => Some((1, -1)),
+                    DrawChar::DiagLeft => Some((1, 1)),
+                    _ => None,
+                };
+                match (&cell.ch, direction) {
+                    (_, Some((dx, dy))) => {
+                        // Only trace from the first cell of a run
+                        let same = |c: Option<&CanvasCell>| c.is_some_and(|c| c.ch == cell.ch && c.color == cell.color);
+                        if !same(cell_at(xi - dx, yi - dy)) {
+                            let (mut ex, mut ey) = (xi, yi);
+                            while same(cell_at(ex + dx, ey + dy)) {
+                                ex += dx;
+                                ey += dy;
+                            }
+                            let (hx, hy) = (dx as f64 / 2.0, dy as f64 / 2.0);
+                            out.push_str(&format!(
+                                "  \\draw[{}] {} -- {};\n",
+                                color_name(cell.color),
+                                point(xi as f64 - hx, yi as f64 - hy),
+                                point(ex as f64 + hx, ey as f64 + hy),
+                            ));
+                        }
+                        x += 1;
+                    }
+                    (DrawChar::Point, _) => {
+                        out.push_str(&format!("  \\fill[{}] {} circle (1pt);\n", color_name(cell.color), point(x as f64, y as f64)));
+                        x += 1;
+                    }
+                    // Point markers get real tikz shapes rather than Unicode text
+                    (DrawChar::Text(marker), _) if matches!(marker, '·' | '○' | '●' | '×') => {
+                        let (name, at) = (color_name(cell.color), point(x as f64, y as f64));
+                        out.push_str(&match marker {
+                            '·' => format!("  \\fill[{}] {} circle (0.5pt);\n", name, at),
+                            '○' => format!("  \\draw[{}] {} circle (2pt);\n", name, at),
+                            '●' => format!("  \\fill[{}] {} circle (2pt);\n", name, at),
+                            _ => format!("  \\node[{}] at {} {{$\\times$}};\n", name, at),
+                        });
+                        x += 1;
+                    }
+                    _ => {
+                        // Gather a run of same-colored text into one node
+                        let start = x;
+                        let mut text = String::new();
+                        while let Some(Some(next)) = row.get(x) {
+                            if next.color != cell.color || !matches!(next.ch, DrawChar::Text(_) | DrawChar::Cross) {
+                                break;
+                            }
+                            text.push(next.ch.glyph());
+                            x += 1;
+                        }
+                        out.push_str(&format!(
+                            "  \\node[{}, anchor=west, inner sep=0pt, font=\\ttfamily] at {} {{{}}};\n",
+                            color_name(cell.color),
+                            point(start as f64 - 0.5, y as f64),
+                            latex_escape(&text),
+                        ));
+                    }
+                }
+            }
+        }
+        for (x, y, text) in &self.annotations {
+            out.push_str(&format!(
+                "  \\node[anchor=west, inner sep=0pt, font=\\ttfamily] at ({:.3}, {:.3}) {{{}}};\n",
+                x, y, latex_escape(text),
+            ));
+        }
+        out.push_str("\\end{tikzpicture}\n");
+        out
+    }
+
+    // Largest text size up to 12pt at which the widest canvas row fits between
+    // 0.5in margins, assuming monospace glyphs 0.6em wide
+    fn fit_text_size(&self, paper: PaperSize) -> f64 {
+        let columns = self.to_ascii().lines().map(|line| line.chars().count()).max().unwrap_or(0);
+        let usable_pt = (paper.width_in() - 1.0) * 72.0;
+        if columns == 0 {
+            return 12.0;
+        }
+        (usable_pt / (columns as f64 * 0.6)).min(12.0)
+    }
+
+    // Shown on the About screen only, so the full scan never runs per frame
+    // while drawing
+    fn canvas_stats(&self) -> String {
+        let mut filled = 0;
+        let mut bounds: Option<(usize, usize, usize, usize)> = None;
+        for (y, row) in self.canvas.iter().enumerate() {
+            for (x, _) in row.iter().enumerate().filter(|(_, cell)| cell.is_some()) {
+                filled += 1;
+                bounds = Some(match bounds {
+                    Some((x0, y0, x1, y1)) => (x0.min(x), y0.min(y), x1.max(x), y1.max(y)),
+                    None => (x, y, x, y),
+                });
+            }
+        }
+        let cell_bytes = std::mem::size_of::<Option<CanvasCell>>();
+        let canvas_bytes = self.canvas.iter().map(|row| row.capacity() * cell_bytes).sum::<usize>();
+        let undo_bytes = self.undo_stack.len() * self.canvas_width * self.virtual_height * cell_bytes;
+        format!(
+            "Canvas: {}x{} cells\nFilled: {}\nDrawing: {}\nMemory: {} KiB\n  + undo: {} KiB",
+            self.canvas_width,
+            self.virtual_height,
+            filled,
+            bounds.map_or("empty".to_string(), |(x0, y0, x1, y1)| format!("{}x{} at ({}, {})", x1 - x0 + 1, y1 - y0 + 1, x0, y0)),
+            canvas_bytes / 1024,
+            undo_bytes / 1024,
+        )
+    }
+
+    // Placeholder written for empty cells: spaces when the chosen one has
+    // since been drawn, so load_drawing can't mistake drawn cells for blanks
+    fn export_empty_cell(&self) -> char {
+        if self.canvas_contains(self.empty_cell) { ' ' } else { self.empty_cell }
+    }
+
+    fn canvas_contains(&self, glyph: char) -> bool {
+        self.canvas.iter().flatten().flatten().any(|cell| cell.ch.glyph() == glyph)
+    }
+
+    // Columns up to the rightmost drawn cell on any row
+    fn content_width(&self) -> usize {
+        self.canvas.iter().filter_map(|row| row.iter().rposition(Option::is_some)).max().map_or(0, |i| i + 1)
+    }
+
+    // Canvas rows as plain text, trailing empty rows trimmed. Trailing spaces
+    // are trimmed too, unless exact_grid pads every row to the drawing's width.
+    // A non-space empty_cell fills the drawing's whole width, like exact_grid.
+    fn to_ascii(&self) -> String {
+        let empty_cell = self.export_empty_cell();
+        let width = self.content_width();
+        let used = self.canvas.iter()
+            .rposition(|row| row.iter().flatten().any(|cell| !cell.ch.glyph().is_whitespace()))
+            .map_or(0, |i| i + 1);
+        let lines: Vec<String> = self.canvas[..used].iter()
+            .map(|row| {
+                let line: String = row.iter()
+                    .map(|cell| cell.as_ref().map_or(empty_cell, |cell| cell.ch.glyph()))
+                    .collect();
+                if self.exact_grid || empty_cell != ' ' {
+                    line.chars().take(width).collect()
                 } else {
-                    // Failed - but don't interrupt the user
+                    line.trim_end().to_string()
+                }
+            })
+            .collect();
+
+        let mut out = String::new();
+        for line in &lines {
+            out.push_str(line);
+            out.push('\n');
+        }
+        out
+    }
+
+    // Stamps and notes are kept in the typst file as comments so they
+    // survive a reload:
+    //   // dravi-stamp: name
+    //   // |<row glyphs>
+    //   // dravi-note: x y text
+    fn stamps_to_comments(&self) -> String {
+        let mut names: Vec<&String> = self.stamps.keys().collect();
+        names.sort();
+
+        let mut out = String::new();
+        for name in names {
+            out.push_str(&format!("// dravi-stamp: {}\n", name));
+            for row in &self.stamps[name] {
+                let line: String = row.iter()
+                    .map(|cell| cell.as_ref().map_or(' ', |cell| cell.ch.glyph()))
+                    .collect();
+                out.push_str(&format!("// |{}\n", line));
+            }
+        }
+        for (x, y, text) in &self.annotations {
+            out.push_str(&format!("// dravi-note: {} {} {}\n", x, y, text));
+        }
+        out
+    }
+
+    fn load_stamps(&mut self, contents: &str) {
+        let mut current: Option<String> = None;
+        for line in contents.lines() {
+            if let Some(note) = line.strip_prefix("// dravi-note: ") {
+                let mut parts = note.splitn(3, ' ');
+                if let (Some(Ok(x)), Some(Ok(y)), Some(text)) = (
+                    parts.next().map(str::parse::<f64>),
+                    parts.next().map(str::parse::<f64>),
+                    parts.next(),
+                ) {
+                    self.annotations.push((x, y, text.to_string()));
+                }
+                current = None;
+            } else if let Some(name) = line.strip_prefix("// dravi-stamp: ") {
+                self.stamps.insert(name.to_string(), Vec::new());
+                current = Some(name.to_string());
+            } else if let (Some(name), Some(row)) = (&current, line.strip_prefix("// |")) {
+                let row = row.chars()
+                    .map(|ch| DrawChar::from_glyph(ch).map(|ch| CanvasCell {
+                        ch,
+                        color: self.current_color,
+                        attrs: TextAttrs::default(),
+                    }))
+                    .collect();
+                if let Some(stamp) = self.stamps.get_mut(name) {
+                    stamp.push(row);
+                }
+            } else {
+                current = None;
+            }
+        }
+    }
+
+    // Drawing rows as typst markup where contiguous same-colored cells share a
+    // `#text(fill: ...)`. Spaces join whichever run they sit in.
+    fn to_colored_typst(&self) -> String {
+        let used = self.canvas.iter().rposition(|row| row.iter().any(Option::is_some)).map_or(0, |i| i + 1);
+        let content_width = self.content_width();
+        let empty_cell = self.export_empty_cell();
+
+        let mut out = String::from("// dravi-drawing\n#block[\n");
+        for row in &self.canvas[..used] {
+            let width = if self.exact_grid || empty_cell != ' ' {
+                content_width
+            } else {
+                row.iter().rposition(Option::is_some).map_or(0, |i| i + 1)
+            };
+            let mut runs: Vec<(Option<Color>, String)> = Vec::new();
+            for cell in &row[..width] {
+                match (cell, runs.last_mut()) {
+                    (None, Some((_, text))) => text.push(empty_cell),
+                    (None, None) => runs.push((None, empty_cell.to_string())),
+                    (Some(cell), Some((color, text))) if color.is_none_or(|c| c == cell.color) => {
+                        *color = Some(cell.color);
+                        text.push(cell.ch.glyph());
+                    }
+                    (Some(cell), _) => runs.push((Some(cell.color), cell.ch.glyph().to_string())),
+                }
+            }
+
+            for (color, text) in runs {
+                let escaped = text.replace('\\', "\\\\").replace('"', "\\\"");
+                match color {
+                    Some(color) => out.push_str(&format!("#text(fill: rgb(\"{}\"), raw(\"{}\"))", color_hex(color), escaped)),
+                    None => out.push_str(&format!("#raw(\"{}\")", escaped)),
+                }
+            }
+            out.push_str(" \\\n");
+        }
+        out.push_str("]\n// end-dravi-drawing\n");
+        out
+    }
+
+    // Inverse of `to_colored_typst`
+    fn load_colored_drawing(&mut self, block: &str, empty: Option<char>) {
+        let rows = block.lines()
+            .skip_while(|line| !line.starts_with("#block["))
+            .skip(1)
+            .take_while(|line| *line != "]");
+        for (y, line) in rows.enumerate().take(self.virtual_height) {
+            let mut x = 0;
+            let mut rest = line;
+            while let Some(start) = rest.find("raw(\"") {
+                let prefix = &rest[..start];
+                let color = prefix.rfind("rgb(\"#")
+                    .and_then(|i| prefix.get(i + 6..i + 12))
+                    .and_then(|hex| self.parse_hex_color(hex));
+
+                let body = &rest[start + 5..];
+                let mut end = body.len();
+                let mut chars = body.char_indices();
+                while let Some((i, ch)) = chars.next() {
+                    let ch = match ch {
+                        '\\' => match chars.next() {
+                            Some((_, escaped)) => escaped,
+                            None => break,
+                        },
+                        '"' => {
+                            end = i + 1;
+                            break;
+                        }
+                        _ => ch,
+                    };
+                    if let Some(draw_char) = DrawChar::from_glyph(ch).filter(|_| Some(ch) != empty) {
+                        let color = color.unwrap_or(self.current_color);
+                        self.put_cell(x, y, CanvasCell { ch: draw_char, color, attrs: TextAttrs::default() });
+                    }
+                    x += 1;
+                }
+                rest = &body[end..];
+            }
+        }
+    }
+
+    // `:e file` replaces the current tab with a saved drawing, including its
+    // stamps and notes, and saves back to that file from then on
+    fn open_drawing(&mut self, path: &str) {
+        let path = if path.is_empty() { self.save_path.clone() } else { path.to_string() };
+        match std::fs::read_to_string(&path) {
+            Ok(contents) => {
+                self.push_undo();
+                self.clear_canvas();
+                self.annotations.clear();
+                self.load_drawing(&contents);
+                self.status_message = Some(format!("Opened {} ({} stamps)", path, self.stamps.len()));
+                self.save_path = path;
+            }
+            Err(err) => self.status_message = Some(format!("Open error: {}: {}", path, err)),
+        }
+    }
+
+    // Load a drawing saved by `save_typst` (the ASCII block) or a plain text file
+    fn load_drawing(&mut self, contents: &str) {
+        self.load_stamps(contents);
+        let empty = contents.lines().find_map(|line| line.strip_prefix("// dravi-empty: ")).and_then(|rest| rest.chars().next());
+
+        if let Some(start) = contents.find("// dravi-drawing") {
+            let end = contents.find("// end-dravi-drawing").unwrap_or(contents.len());
+            self.load_colored_drawing(&contents[start..end], empty);
+            self.canvas_version += 1;
+            return;
+        }
+
+        let mut fenced = contents.split("```");
+        let drawing = match (fenced.next(), fenced.next()) {
+            (Some(_), Some(block)) => block.strip_prefix('\n').unwrap_or(block),
+            // Our own export without an ASCII block holds no drawing
+            _ if contents.contains("#set page(") || contents.contains("#figure(") => "",
+            _ => contents,
+        };
+
+        for (y, line) in drawing.lines().enumerate().take(self.virtual_height) {
+            for (x, ch) in line.chars().enumerate().take(self.canvas_width) {
+                match DrawChar::from_glyph(ch).filter(|_| Some(ch) != empty) {
+                    Some(draw_char) => self.set_cell(x, y, draw_char),
+                    None => self.clear_cell(x, y),
+                };
+            }
+        }
+        self.canvas_version += 1;
+    }
+
+    // Compile in the background so big documents don't freeze the editor.
+    // Saves made while typst runs are coalesced into one follow-up compile
+    // per file, so a region :export doesn't drop the main file's compile.
+    fn compile_to_pdf(&mut self) {
+        use std::env;
+
+        let current_dir = env::current_dir().unwrap();
+        let typ_file = current_dir.join(&self.save_path).display().to_string();
+        if self.pdf_compile.is_some() {
+            if !self.compile_queued.contains(&typ_file) {
+                self.compile_queued.push(typ_file);
+            }
+        } else {
+            self.spawn_compile(typ_file);
+        }
+    }
+
+    fn spawn_compile(&mut self, typ_file: String) {
+        use std::process::{Command, Stdio};
+
+        // typst command not found - ignore silently
+        if let Ok(child) = Command::new("typst")
+            .args(["compile", &typ_file])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+        {
+            self.pdf_compile = Some((child, typ_file));
+        }
+    }
+
+    // Called every frame; true when a compile finished and the status changed
+    fn poll_compile(&mut self) -> bool {
+        let Some((child, typ_file)) = &mut self.pdf_compile else {
+            return false;
+        };
+        let status = match child.try_wait() {
+            Ok(None) => return false,
+            Ok(Some(status)) => status.success(),
+            Err(_) => false,
+        };
+        let pdf = Path::new(typ_file).with_extension("pdf");
+        let name = pdf.file_name().map(|name| name.to_string_lossy().into_owned()).unwrap_or_default();
+        self.status_message = Some(if status {
+            format!("Compiled {}", name)
+        } else {
+            format!("typst could not compile {}", name)
+        });
+        self.pdf_compile = None;
+        if !self.compile_queued.is_empty() {
+            let queued = self.compile_queued.remove(0);
+            self.spawn_compile(queued);
+        }
+        true
+    }
+
+    // On quit, let the running compile and any queued ones finish so the
+    // last save still gets its PDF and no typst process is left unreaped
+    fn finish_compiles(&mut self) {
+        while let Some((mut child, _)) = self.pdf_compile.take() {
+            let _ = child.wait();
+            if !self.compile_queued.is_empty() {
+                let queued = self.compile_queued.remove(0);
+                self.spawn_compile(queued);
+            }
+        }
+    }
+
+    fn clear_row(&mut self) {
+        let y = self.cursor_y as usize;
+        if self.canvas.get(y).is_some_and(|row| row.iter().any(Option::is_some)) {
+            self.push_undo();
+            for x in 0..self.canvas_width {
+                self.clear_cell(x, y);
+            }
+        }
+    }
+
+    fn clear_column(&mut self) {
+        let x = self.cursor_x as usize;
+        if self.canvas.iter().any(|row| row.get(x).is_some_and(Option::is_some)) {
+            self.push_undo();
+            for y in 0..self.virtual_height {
+                self.clear_cell(x, y);
+            }
+        }
+    }
+
+    // `:heatmap file` reads x,y data points (comma or space separated, one per
+    // line) and counts how many land in each cell. Counts add up over several
+    // files; every counted cell is redrawn with the glyph for its share of the
+    // busiest cell. `:heatmap clear` forgets the counts and erases the shades.
+    fn load_heatmap(&mut self, arg: &str) {
+        if arg == "clear" {
+            if self.heat_counts.is_empty() {
+                self.status_message = Some("No heatmap to clear".to_string());
+                return;
+            }
+            self.push_undo();
+            for (x, y) in std::mem::take(&mut self.heat_counts).into_keys() {
+                // Leave cells drawn over since the heatmap was loaded
+                let shaded = self.canvas[y][x].as_ref().is_some_and(|cell| matches!(cell.ch, DrawChar::Text(ch) if HEAT_GLYPHS.contains(&ch)));
+                if shaded {
+                    self.clear_cell(x, y);
                 }
             }
-            Err(_) => {
-                // typst command not found - ignore silently
+            self.status_message = Some("Heatmap cleared".to_string());
+            return;
+        }
+        let contents = match std::fs::read_to_string(arg) {
+            Ok(contents) => contents,
+            Err(err) => {
+                self.status_message = Some(format!("heatmap error: {}: {}", arg, err));
+                return;
+            }
+        };
+
+        let mut hits: HashMap<(usize, usize), u32> = HashMap::new();
+        let (mut points, mut skipped, mut outside) = (0, 0, 0);
+        for line in contents.lines().map(str::trim) {
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut fields = line.split(|c: char| c == ',' || c.is_whitespace()).filter(|f| !f.is_empty());
+            let (Some(Ok(x)), Some(Ok(y))) = (fields.next().map(str::parse::<f64>), fields.next().map(str::parse::<f64>)) else {
+                // Header rows and anything else that isn't a pair of numbers
+                skipped += 1;
+                continue;
+            };
+            let (cx, cy) = self.data_to_cell(x, y);
+            let (cx, cy) = (cx.round(), cy.round());
+            if !(cx >= 0.0 && cy >= 0.0 && cx < self.canvas_width as f64 && cy < self.virtual_height as f64) {
+                outside += 1;
+                continue;
+            }
+            *hits.entry((cx as usize, cy as usize)).or_insert(0) += 1;
+            points += 1;
+        }
+
+        if points == 0 {
+            self.status_message = Some(format!(
+                "heatmap error: no points on the canvas in {} ({} unreadable lines, {} off the canvas)",
+                arg, skipped, outside
+            ));
+            return;
+        }
+
+        self.push_undo();
+        for (cell, count) in hits {
+            *self.heat_counts.entry(cell).or_insert(0) += count;
+        }
+        let max = self.heat_counts.values().copied().max().unwrap_or(0);
+        let cells: Vec<((usize, usize), u32)> = self.heat_counts.iter().map(|(&cell, &count)| (cell, count)).collect();
+        for ((x, y), count) in cells {
+            // Merge mode mustn't freeze a cell at a lighter shade
+            let cell = CanvasCell {
+                ch: DrawChar::Text(HEAT_GLYPHS[heat_level(count, max)]),
+                color: self.current_color,
+                attrs: TextAttrs::default(),
+            };
+            self.write_cell(x, y, Some(cell));
+        }
+        self.status_message = Some(format!(
+            "Heatmap: {} points in {} cells, busiest {} ({} unreadable lines, {} off the canvas)",
+            points, self.heat_counts.len(), max, skipped, outside
+        ));
+    }
+
+    fn toggle_macro_recording(&mut self) {
+        match self.macro_recording.take() {
+            Some(keys) => {
+                self.status_message = Some(format!("Recorded {} keys (@ or :macro N to replay)", keys.len()));
+                self.macro_keys = keys;
+            }
+            None => self.macro_recording = Some(Vec::new()),
+        }
+    }
+
+    // Feed the recorded keys back through handle_key `count` times. A macro
+    // that reaches @ or :macro itself would never end, so replays don't nest;
+    // replay also stops if the macro quits.
+    fn play_macro(&mut self, count: usize) {
+        if self.macro_running {
+            return;
+        }
+        if self.macro_keys.is_empty() {
+            self.status_message = Some("No macro recorded (Q starts recording)".to_string());
+            return;
+        }
+        let keys = self.macro_keys.clone();
+        self.macro_running = true;
+        'replay: for _ in 0..count {
+            for &key in &keys {
+                self.handle_key(key);
+                if self.should_quit {
+                    break 'replay;
+                }
             }
         }
+        self.macro_running = false;
+    }
+
+    // Copy the cursor's row over the row below and step down onto it, so
+    // repeated presses stack copies of a table row or tick line
+    fn duplicate_row(&mut self) {
+        let y = self.cursor_y as usize;
+        if y + 1 >= self.virtual_height {
+            self.status_message = Some(format!("No row below {} (:grow to add rows)", y));
+            return;
+        }
+        self.push_undo();
+        for x in 0..self.canvas_width {
+            self.write_cell(x, y + 1, self.canvas[y][x].clone());
+        }
+        self.move_cursor(0.0, 1.0);
     }
 
     fn clear_canvas(&mut self) {
@@ -597,117 +4154,764 @@ impl App {
                 *pixel = None;
             }
         }
-        // Also clear typst content
+        self.canvas_version += 1;
+        // Also clear typst content and the heatmap behind the legend
         self.typst_content.clear();
+        self.heat_counts.clear();
+    }
+}
+
+// Approximate typst math with Unicode for on-canvas display, e.g.
+// `$x^2 + sqrt(pi)$` becomes `x² + √(π)`
+fn prettify_math(source: &str) -> String {
+    const SUPERSCRIPTS: &str = "⁰¹²³⁴⁵⁶⁷⁸⁹";
+    const SUBSCRIPTS: &str = "₀₁₂₃₄₅₆₇₈₉";
+
+    let chars: Vec<char> = source.trim_matches('$').chars().collect();
+    let mut out = String::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let ch = chars[i];
+        if ch.is_alphabetic() {
+            let start = i;
+            while i < chars.len() && chars[i].is_alphabetic() {
+                i += 1;
+            }
+            let word: String = chars[start..i].iter().collect();
+            out.push_str(match word.as_str() {
+                "sqrt" => "√",
+                "pi" => "π",
+                "theta" => "θ",
+                "alpha" => "α",
+                "beta" => "β",
+                "gamma" => "γ",
+                "delta" => "δ",
+                "lambda" => "λ",
+                "mu" => "μ",
+                "sigma" => "σ",
+                "phi" => "φ",
+                "omega" => "ω",
+                "sum" => "∑",
+                "integral" => "∫",
+                "infinity" | "oo" => "∞",
+                _ => &word,
+            });
+            continue;
+        }
+
+        let script = match ch {
+            '^' => Some(SUPERSCRIPTS),
+            '_' => Some(SUBSCRIPTS),
+            _ => None,
+        };
+        match script {
+            Some(digits) if chars.get(i + 1).is_some_and(|c| c.is_ascii_digit()) => {
+                i += 1;
+                while let Some(digit) = chars.get(i).and_then(|c| c.to_digit(10)) {
+                    out.extend(digits.chars().nth(digit as usize));
+                    i += 1;
+                }
+            }
+            _ => {
+                let pair: String = chars[i..(i + 2).min(chars.len())].iter().collect();
+                match pair.as_str() {
+                    "<=" => out.push('≤'),
+                    ">=" => out.push('≥'),
+                    "!=" => out.push('≠'),
+                    "->" => out.push('→'),
+                    _ => {
+                        out.push(if ch == '*' { '·' } else { ch });
+                        i += 1;
+                        continue;
+                    }
+                }
+                i += 2;
+            }
+        }
+    }
+    out
+}
+
+fn color_hex(color: Color) -> String {
+    match color {
+        Color::Rgb(r, g, b) => format!("#{:02x}{:02x}{:02x}", r, g, b),
+        _ => color_hex(DEFAULT_COLOR),
+    }
+}
+
+// Whether a `typst` executable is reachable through PATH
+fn typst_on_path() -> bool {
+    std::env::var_os("PATH").is_some_and(|paths| {
+        std::env::split_paths(&paths).any(|dir| dir.join("typst").is_file())
+    })
+}
+
+// drawing.typ -> drawing-YYYYMMDD-HHMMSS.typ (UTC)
+fn snapshot_path(save_path: &str, now: SystemTime) -> String {
+    let secs = now.duration_since(UNIX_EPOCH).map(|d| d.as_secs()).unwrap_or(0);
+    let (days, rem) = ((secs / 86_400) as i64, secs % 86_400);
+    // Days since the epoch to a civil date (Howard Hinnant's algorithm)
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    let stamp = format!(
+        "{:04}{:02}{:02}-{:02}{:02}{:02}",
+        year, month, day, rem / 3600, rem / 60 % 60, rem % 60
+    );
+    let path = Path::new(save_path);
+    let stem = path.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+    let ext = path.extension().map(|e| e.to_string_lossy().into_owned()).unwrap_or_else(|| "typ".to_string());
+    path.with_file_name(format!("{}-{}.{}", stem, stamp, ext)).display().to_string()
+}
+
+// Whether an (origin_x, origin_y, scale_x, scale_y) view is finite and its
+// scales lie within the range < > [ ] can reach
+fn view_in_range((origin_x, origin_y, scale_x, scale_y): (f64, f64, f64, f64)) -> bool {
+    origin_x.is_finite()
+        && origin_y.is_finite()
+        && (MIN_SCALE..=MAX_SCALE).contains(&scale_x)
+        && (MIN_SCALE..=MAX_SCALE).contains(&scale_y)
+}
+
+// Origin and scale putting data values (dx, dy) at cells (cx, cy) for both
+// points, from data = (cell_x - origin_x) * scale_x and
+// data = (origin_y - cell_y) * y_sign * scale_y
+fn solve_calibration(
+    (ax, ay, adx, ady): (f64, f64, f64, f64),
+    (bx, by, bdx, bdy): (f64, f64, f64, f64),
+    y_sign: f64,
+) -> std::result::Result<(f64, f64, f64, f64), String> {
+    if ax == bx || ay == by || adx == bdx || ady == bdy {
+        return Err("the two points must differ in both x and y".to_string());
+    }
+    let scale_x = (bdx - adx) / (bx - ax);
+    let scale_y = (bdy - ady) / ((ay - by) * y_sign);
+    if scale_x < 0.0 || scale_y < 0.0 {
+        return Err("values must increase the same way as the axes".to_string());
+    }
+    Ok((ax - adx / scale_x, ay + ady / (y_sign * scale_y), scale_x, scale_y))
+}
+
+// "auto" (or nothing) keeps the per-position default, otherwise 0-6 decimals
+fn parse_precision(value: &str) -> Option<Option<usize>> {
+    match value.trim() {
+        "" | "auto" => Some(None),
+        digits => digits.parse::<usize>().ok().filter(|&p| p <= 6).map(Some),
+    }
+}
+
+fn parse_flag(value: &str) -> Option<bool> {
+    match value.to_lowercase().as_str() {
+        "1" | "on" | "true" | "yes" => Some(true),
+        "0" | "off" | "false" | "no" => Some(false),
+        _ => None,
+    }
+}
+
+// Parsed math expression, e.g. `2x^2 + sin(x) / 3`
+#[derive(Clone, Debug)]
+enum Expr {
+    Num(f64),
+    Var(String),
+    Neg(Box<Expr>),
+    Binary(char, Box<Expr>, Box<Expr>),
+    Call(String, Box<Expr>),
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Token {
+    Num(f64),
+    Ident(String),
+    Op(char),
+    LParen,
+    RParen,
+}
+
+const FUNCTIONS: &[&str] = &[
+    "sin", "cos", "tan", "asin", "acos", "atan", "sqrt", "abs", "ln", "log", "exp", "floor", "ceil",
+];
+
+impl Expr {
+    // Parse an expression that may only reference the given variables
+    fn parse(source: &str, vars: &[&str]) -> std::result::Result<Expr, String> {
+        let tokens = tokenize(source)?;
+        let mut parser = ExprParser { tokens, pos: 0, vars };
+        let expr = parser.expr()?;
+        match parser.tokens.get(parser.pos) {
+            None => Ok(expr),
+            Some(token) => Err(format!("unexpected {:?}", token)),
+        }
+    }
+
+    fn eval(&self, vars: &[(&str, f64)]) -> f64 {
+        match self {
+            Expr::Num(value) => *value,
+            Expr::Var(name) => match name.as_str() {
+                "pi" => std::f64::consts::PI,
+                "e" => std::f64::consts::E,
+                _ => vars.iter().find(|(var, _)| var == name).map_or(f64::NAN, |(_, value)| *value),
+            },
+            Expr::Neg(inner) => -inner.eval(vars),
+            Expr::Binary(op, lhs, rhs) => {
+                let (a, b) = (lhs.eval(vars), rhs.eval(vars));
+                match op {
+                    '+' => a + b,
+                    '-' => a - b,
+                    '*' => a * b,
+                    '/' => a / b,
+                    '^' => a.powf(b),
+                    _ => f64::NAN,
+                }
+            }
+            Expr::Call(name, arg) => {
+                let a = arg.eval(vars);
+                match name.as_str() {
+                    "sin" => a.sin(),
+                    "cos" => a.cos(),
+                    "tan" => a.tan(),
+                    "asin" => a.asin(),
+                    "acos" => a.acos(),
+                    "atan" => a.atan(),
+                    "sqrt" => a.sqrt(),
+                    "abs" => a.abs(),
+                    "ln" => a.ln(),
+                    "log" => a.log10(),
+                    "exp" => a.exp(),
+                    "floor" => a.floor(),
+                    "ceil" => a.ceil(),
+                    _ => f64::NAN,
+                }
+            }
+        }
+    }
+}
+
+fn tokenize(source: &str) -> std::result::Result<Vec<Token>, String> {
+    let chars: Vec<char> = source.chars().collect();
+    let mut tokens = Vec::new();
+    let mut i = 0;
+    while i < chars.len() {
+        let ch = chars[i];
+        if ch.is_whitespace() {
+            i += 1;
+        } else if ch.is_ascii_digit() || ch == '.' {
+            let start = i;
+            while i < chars.len() && (chars[i].is_ascii_digit() || chars[i] == '.') {
+                i += 1;
+            }
+            let text: String = chars[start..i].iter().collect();
+            let value = text.parse::<f64>().map_err(|_| format!("bad number `{}`", text))?;
+            tokens.push(Token::Num(value));
+        } else if ch.is_alphabetic() {
+            let start = i;
+            while i < chars.len() && chars[i].is_alphanumeric() {
+                i += 1;
+            }
+            tokens.push(Token::Ident(chars[start..i].iter().collect()));
+        } else {
+            tokens.push(match ch {
+                '(' => Token::LParen,
+                ')' => Token::RParen,
+                '+' | '-' | '*' | '/' | '^' => Token::Op(ch),
+                _ => return Err(format!("unexpected `{}`", ch)),
+            });
+            i += 1;
+        }
+    }
+    Ok(tokens)
+}
+
+struct ExprParser<'a> {
+    tokens: Vec<Token>,
+    pos: usize,
+    vars: &'a [&'a str],
+}
+
+impl ExprParser<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.pos)
+    }
+
+    fn expr(&mut self) -> std::result::Result<Expr, String> {
+        let mut lhs = self.term()?;
+        while let Some(Token::Op(op @ ('+' | '-'))) = self.peek().cloned() {
+            self.pos += 1;
+            lhs = Expr::Binary(op, Box::new(lhs), Box::new(self.term()?));
+        }
+        Ok(lhs)
+    }
+
+    fn term(&mut self) -> std::result::Result<Expr, String> {
+        let mut lhs = self.unary()?;
+        loop {
+            match self.peek() {
+                Some(Token::Op(op @ ('*' | '/'))) => {
+                    let op = *op;
+                    self.pos += 1;
+                    lhs = Expr::Binary(op, Box::new(lhs), Box::new(self.unary()?));
+                }
+                // Implicit multiplication: `2x`, `3(x + 1)`, `x sin(x)`
+                Some(Token::Num(_) | Token::Ident(_) | Token::LParen) => {
+                    lhs = Expr::Binary('*', Box::new(lhs), Box::new(self.power()?));
+                }
+                _ => return Ok(lhs),
+            }
+        }
+    }
+
+    fn unary(&mut self) -> std::result::Result<Expr, String> {
+        match self.peek() {
+            Some(Token::Op('-')) => {
+                self.pos += 1;
+                Ok(Expr::Neg(Box::new(self.unary()?)))
+            }
+            Some(Token::Op('+')) => {
+                self.pos += 1;
+                self.unary()
+            }
+            _ => self.power(),
+        }
+    }
+
+    fn power(&mut self) -> std::result::Result<Expr, String> {
+        let base = self.atom()?;
+        if let Some(Token::Op('^')) = self.peek() {
+            self.pos += 1;
+            return Ok(Expr::Binary('^', Box::new(base), Box::new(self.unary()?)));
+        }
+        Ok(base)
+    }
+
+    fn atom(&mut self) -> std::result::Result<Expr, String> {
+        let token = self.peek().cloned().ok_or("unexpected end of expression")?;
+        self.pos += 1;
+        match token {
+            Token::Num(value) => Ok(Expr::Num(value)),
+            Token::LParen => {
+                let inner = self.expr()?;
+                match self.peek() {
+                    Some(Token::RParen) => {
+                        self.pos += 1;
+                        Ok(inner)
+                    }
+                    _ => Err("missing `)`".to_string()),
+                }
+            }
+            Token::Ident(name) if FUNCTIONS.contains(&name.as_str()) => {
+                // `sin(x)^2` squares the call, `sin x^2` squares the argument
+                let arg = match self.peek() {
+                    Some(Token::LParen) => self.atom()?,
+                    _ => self.power()?,
+                };
+                Ok(Expr::Call(name, Box::new(arg)))
+            }
+            Token::Ident(name) if name == "pi" || name == "e" || self.vars.contains(&name.as_str()) => {
+                Ok(Expr::Var(name))
+            }
+            Token::Ident(name) => Err(format!("unknown name `{}`", name)),
+            token => Err(format!("unexpected {:?}", token)),
+        }
+    }
+}
+
+// Liang–Barsky: the (t0, t1) range of the segment from `a` to `b` that lies
+// inside the box `min`..`max`, or None when it misses it or isn't finite
+fn clip_segment(a: (f64, f64), b: (f64, f64), min: (f64, f64), max: (f64, f64)) -> Option<(f64, f64)> {
+    if ![a.0, a.1, b.0, b.1].iter().all(|v| v.is_finite()) {
+        return None;
+    }
+    let (dx, dy) = (b.0 - a.0, b.1 - a.1);
+    let (mut t0, mut t1) = (0.0_f64, 1.0_f64);
+    for (p, q) in [(-dx, a.0 - min.0), (dx, max.0 - a.0), (-dy, a.1 - min.1), (dy, max.1 - a.1)] {
+        if p == 0.0 {
+            if q < 0.0 {
+                return None;
+            }
+        } else if p < 0.0 {
+            t0 = t0.max(q / p);
+        } else {
+            t1 = t1.min(q / p);
+        }
+    }
+    (t0 <= t1).then_some((t0, t1))
+}
+
+// Cells on the line between two cells, using Bresenham's line algorithm
+fn line_points(x0: i32, y0: i32, x1: i32, y1: i32) -> Vec<(i32, i32)> {
+    let dx = (x1 - x0).abs();
+    let dy = -(y1 - y0).abs();
+    let sx = if x0 < x1 { 1 } else { -1 };
+    let sy = if y0 < y1 { 1 } else { -1 };
+    let mut err = dx + dy;
+    
+    let mut x = x0;
+    let mut y = y0;
+    let mut points = Vec::new();
+    
+    loop {
+        points.push((x, y));
+        
+        if x == x1 && y == y1 { break; }
+        
+        let e2 = 2 * err;
+        if e2 >= dy {
+            err += dy;
+            x += sx;
+        }
+        if e2 <= dx {
+            err += dx;
+            y += sy;
+        }
+    }
+    points
+}
+
+// Interior cells of a polygon using the even-odd rule. Each row is sampled at
+// its cell index, and edges are half-open so shared vertices count once.
+// Only cells inside a width x height canvas are returned, however far off it
+// the vertices lie.
+fn scanline_fill(vertices: &[(f64, f64)], width: i32, height: i32) -> Vec<(i32, i32)> {
+    let mut cells = Vec::new();
+    if vertices.len() < 3 || vertices.iter().any(|v| !v.0.is_finite() || !v.1.is_finite()) {
+        return cells;
+    }
+
+    let min_y = vertices.iter().map(|v| v.1).fold(f64::INFINITY, f64::min).max(0.0) as i32;
+    let max_y = vertices.iter().map(|v| v.1).fold(f64::NEG_INFINITY, f64::max).min(height as f64 - 1.0) as i32;
+
+    for y in min_y..=max_y {
+        let scan_y = y as f64;
+        let mut crossings = Vec::new();
+        for (i, &(x0, y0)) in vertices.iter().enumerate() {
+            let (x1, y1) = vertices[(i + 1) % vertices.len()];
+            if (y0 <= scan_y && scan_y < y1) || (y1 <= scan_y && scan_y < y0) {
+                crossings.push(x0 + (scan_y - y0) * (x1 - x0) / (y1 - y0));
+            }
+        }
+        crossings.sort_by(|a, b| a.total_cmp(b));
+
+        for pair in crossings.chunks_exact(2) {
+            let first = pair[0].ceil().max(0.0) as i32;
+            let last = pair[1].floor().min(width as f64 - 1.0) as i32;
+            for x in first..=last {
+                cells.push((x, y));
+            }
+        }
     }
+    cells
+}
+
+// Popups float centered over the canvas, sized to their text plus borders
+fn popup_rect(content: &str, area: Rect) -> Rect {
+    let width = content.lines().map(|line| line.chars().count()).max().unwrap_or(0) as u16 + 2;
+    let height = content.lines().count() as u16 + 2;
+    centered_rect(width, height, area)
+}
+
+fn centered_rect(width: u16, height: u16, area: Rect) -> Rect {
+    let (width, height) = (width.min(area.width), height.min(area.height));
+    Rect::new(area.x + (area.width - width) / 2, area.y + (area.height - height) / 2, width, height)
 }
 
-fn ui(f: &mut Frame, app: &App) {
-    let main_chunks = Layout::default()
+// Returns where the minimap was drawn, for mouse clicks
+fn ui(f: &mut Frame, app: &App) -> Option<Rect> {
+    let theme = app.theme;
+    let tab_bar_height = if app.tabs.len() > 1 { 1 } else { 0 };
+    let outer_chunks = Layout::default()
         .direction(Direction::Vertical)
-        .constraints([Constraint::Min(0), Constraint::Length(3)].as_ref())
+        .constraints([Constraint::Length(tab_bar_height), Constraint::Min(0), Constraint::Length(3)].as_ref())
         .split(f.size());
-        
-    let chunks = if app.mode == AppMode::Settings {
-        // Split main area for settings popup
-        Layout::default()
-            .direction(Direction::Horizontal)
-            .constraints([Constraint::Min(0), Constraint::Length(30)].as_ref())
-            .split(main_chunks[0])
-    } else {
-        Layout::default()
-            .direction(Direction::Horizontal)
-            .constraints([Constraint::Min(0)].as_ref())
-            .split(main_chunks[0])
-    };
+    let main_chunks = &outer_chunks[1..];
 
+    if app.tabs.len() > 1 {
+        let mut spans = Vec::new();
+        for (i, tab) in app.tabs.iter().enumerate() {
+            let name = if i == app.active_tab { &app.save_path } else { &tab.save_path };
+            let style = if i == app.active_tab {
+                Style::default().fg(theme.background).bg(theme.accent)
+            } else {
+                Style::default().fg(theme.accent)
+            };
+            spans.push(Span::styled(format!(" {}:{} ", i + 1, name), style));
+        }
+        f.render_widget(Paragraph::new(TextLine::from(spans)).style(Style::default().bg(theme.background)), outer_chunks[0]);
+    }
+        
+    // Cursor readout on the bottom border stays put whatever the mode
+    let mut canvas_block = Block::default().title("DraVi - Mathematical Drawing Tool");
+    if app.show_coordinates {
+        canvas_block = canvas_block.title(
+            Title::from(format!(" {} ", app.get_current_coordinates()))
+                .position(Position::Bottom)
+                .alignment(Alignment::Right),
+        );
+    }
     let canvas_widget = Canvas::default()
-        .block(Block::default()
-            .title("DraVi - Mathematical Drawing Tool")
+        .block(canvas_block
             .borders(Borders::ALL)
-            .style(Style::default().fg(Color::Rgb(100, 149, 237)).bg(Color::Black)))
+            .style(Style::default().fg(theme.accent).bg(theme.background)))
         .x_bounds([0.0, app.canvas_width as f64])
         .y_bounds([0.0, app.canvas_height as f64])
-        .background_color(Color::Black)
+        .background_color(theme.background)
         .paint(|ctx| {
-            
+            // Dashed gridlines at the major ticks, under everything else
+            if app.show_gridlines {
+                let dim = theme.grid;
+                let (width, height) = (app.canvas_width as f64, app.canvas_height as f64);
+                let (left, _) = app.cell_to_data(0.0, 0.0);
+                let (right, _) = app.cell_to_data(width - 1.0, 0.0);
+                for value in App::grid_values(left, right, app.tick_step(app.scale_x), app.grid_offset_x) {
+                    let (x, _) = app.data_to_cell(value, 0.0);
+                    let mut y = 0.0;
+                    while y < height {
+                        ctx.draw(&Line { x1: x, y1: y, x2: x, y2: (y + 0.5).min(height - 1.0), color: dim });
+                        y += 2.0;
+                    }
+                }
+                let (_, top) = app.cell_to_data(0.0, app.scroll_y as f64);
+                let (_, bottom) = app.cell_to_data(0.0, (app.scroll_y + app.canvas_height - 1) as f64);
+                for value in App::grid_values(bottom, top, app.tick_step(app.scale_y), app.grid_offset_y) {
+                    let (_, row) = app.data_to_cell(0.0, value);
+                    let y = height - 1.0 - (row - app.scroll_y as f64);
+                    let mut x = 0.0;
+                    while x < width {
+                        ctx.draw(&Line { x1: x, y1: y, x2: (x + 1.0).min(width - 1.0), y2: y, color: dim });
+                        x += 3.0;
+                    }
+                }
+            }
+
             // Draw axes if enabled
-            if app.show_axes {
+            if app.show_axes && app.axis_style == AxisStyle::Full {
                 // X-axis (horizontal line through origin)
                 ctx.draw(&Line {
                     x1: 0.0,
-                    y1: app.canvas_height as f64 - 1.0 - app.origin_y,
+                    y1: app.screen_y(app.origin_y),
                     x2: app.canvas_width as f64 - 1.0,
-                    y2: app.canvas_height as f64 - 1.0 - app.origin_y,
-                    color: Color::Red,
+                    y2: app.screen_y(app.origin_y),
+                    color: theme.axis,
                 });
                 
                 // Y-axis (vertical line through origin)
                 ctx.draw(&Line {
-                    x1: app.origin_x,
+                    x1: app.origin_x.floor(),
                     y1: 0.0,
-                    x2: app.origin_x,
+                    x2: app.origin_x.floor(),
                     y2: app.canvas_height as f64 - 1.0,
-                    color: Color::Red,
+                    color: theme.axis,
                 });
+            }
+
+            if app.show_axes && app.axis_style == AxisStyle::Ticks {
+                let axis_x = app.origin_x.floor();
+                let axis_y = app.screen_y(app.origin_y);
+
+                // Ticks across the x-axis at the grid values visible on screen
+                let (left, _) = app.cell_to_data(0.0, 0.0);
+                let (right, _) = app.cell_to_data(app.canvas_width as f64 - 1.0, 0.0);
+                for value in App::grid_values(left, right, app.tick_step(app.scale_x), app.grid_offset_x) {
+                    let (x, _) = app.data_to_cell(value, 0.0);
+                    ctx.draw(&Line { x1: x, y1: axis_y - 0.5, x2: x, y2: axis_y + 0.5, color: theme.axis });
+                }
+
+                // Ticks across the y-axis
+                let (_, top) = app.cell_to_data(0.0, app.scroll_y as f64);
+                let (_, bottom) = app.cell_to_data(0.0, (app.scroll_y + app.canvas_height - 1) as f64);
+                for value in App::grid_values(bottom, top, app.tick_step(app.scale_y), app.grid_offset_y) {
+                    let (_, row) = app.data_to_cell(0.0, value);
+                    let y = app.canvas_height as f64 - 1.0 - (row - app.scroll_y as f64);
+                    ctx.draw(&Line { x1: axis_x - 0.5, y1: y, x2: axis_x + 0.5, y2: y, color: theme.axis });
+                }
+            }
 
+            if app.show_axes && app.axis_style != AxisStyle::Hidden {
                 // Origin marker
                 ctx.draw(&Points {
-                    coords: &[(app.origin_x, app.canvas_height as f64 - 1.0 - app.origin_y)],
-                    color: Color::Red,
+                    coords: &[(app.origin_x.floor(), app.screen_y(app.origin_y))],
+                    color: theme.axis,
                 });
             }
 
-            // Draw characters (only visible portion)
-            for (y, row) in app.canvas.iter().enumerate().skip(app.scroll_y).take(app.canvas_height) {
-                for (x, cell) in row.iter().enumerate() {
-                    if let Some(draw_char) = cell {
-                        match draw_char {
-                            DrawChar::Text(ch) => {
-                                ctx.print(
-                                    x as f64,
-                                    (app.canvas_height as f64 - 1.0 - ((y - app.scroll_y) as f64)),
-                                    Span::styled(ch.to_string(), Style::default().fg(app.current_color)),
-                                );
-                            }
-                            _ => {
-                                let char_to_draw = match draw_char {
-                                    DrawChar::Point => "•",
-                                    DrawChar::Horizontal => "-",
-                                    DrawChar::Vertical => "|",
-                                    DrawChar::Cross => "+",
-                                    DrawChar::DiagRight => "/",
-                                    DrawChar::DiagLeft => "\\",
-                                    DrawChar::Text(_) => unreachable!(),
-                                };
-                                ctx.print(
-                                    x as f64,
-                                    (app.canvas_height as f64 - 1.0 - ((y - app.scroll_y) as f64)),
-                                    Span::styled(char_to_draw, Style::default().fg(app.current_color)),
-                                );
-                            }
+            // Draw characters (only visible portion, prepared by refresh_render_cache)
+            for (row_offset, row) in app.render_cache.iter().enumerate() {
+                for (x, span) in row {
+                    ctx.print(
+                        *x as f64,
+                        app.canvas_height as f64 - 1.0 - row_offset as f64,
+                        span.clone(),
+                    );
+                }
+            }
+
+
+            // Pending first calibration point
+            if let Some((x, y, _, _)) = app.calibration_mark {
+                let row = y.floor() - app.scroll_y as f64;
+                if (0.0..app.canvas_height as f64).contains(&row) {
+                    ctx.print(x.floor(), app.screen_y(y), Span::styled("◎", Style::default().fg(theme.highlight)));
+                }
+            }
+
+            // Managed functions, recomputed so they track origin and scale
+            let (width, limit) = (app.canvas_width as f64, app.virtual_height as f64);
+            let screen = |row: f64| app.canvas_height as f64 - 1.0 - (row - app.scroll_y as f64);
+            for function in &app.functions {
+                let cells = app.curve_cells(function);
+                for pair in cells.windows(2) {
+                    if let [Some((x0, y0)), Some((x1, y1))] = *pair {
+                        if [y0, y1].iter().all(|y| y.abs() < 2.0 * limit) && [x0, x1].iter().all(|x| x.abs() < 2.0 * width) {
+                            ctx.draw(&Line { x1: x0, y1: screen(y0), x2: x1, y2: screen(y1), color: function.color });
                         }
                     }
                 }
             }
 
+            // Notes follow their data point through origin and scale changes
+            for (x, y, text) in &app.annotations {
+                let (cx, cy) = app.data_to_cell(*x, *y);
+                let row = cy.floor() - app.scroll_y as f64;
+                if cx >= 0.0 && cx < app.canvas_width as f64 && (0.0..app.canvas_height as f64).contains(&row) {
+                    ctx.print(cx.floor(), app.screen_y(cy), Span::styled(text.clone(), Style::default().fg(theme.text)));
+                }
+            }
 
-            // Only draw cursor if it's visible
-            if app.cursor_y >= app.scroll_y as f64 && app.cursor_y < (app.scroll_y + app.canvas_height) as f64 {
-                ctx.draw(&Points {
-                    coords: &[(app.cursor_x, (app.canvas_height as f64 - 1.0 - (app.cursor_y - app.scroll_y as f64)))],
-                    color: match app.mode {
-                        AppMode::Drawing => Color::Rgb(255, 105, 180), // Hot pink
-                        AppMode::Selection => Color::Yellow,
-                        AppMode::ColorSelection => Color::Cyan,
-                        AppMode::TypstInput => Color::Green,
-                        AppMode::CoordinateInput => Color::Magenta,
-                        AppMode::Settings => Color::Blue,
-                        AppMode::PdfRender => Color::White,
-                    },
+            // Rubber band from the last polyline vertex to the cursor
+            if let Some(&(x0, y0)) = app.polyline.last() {
+                let (x1, y1) = app.polyline_target();
+                ctx.draw(&Line {
+                    x1: x0.floor(),
+                    y1: app.screen_y(y0),
+                    x2: x1.floor(),
+                    y2: app.screen_y(y1),
+                    color: theme.dim,
                 });
             }
 
+            // Line end the next vertex would snap to
+            if app.mode == AppMode::Polyline {
+                let (x, y) = app.polyline_target();
+                if let Some((ex, ey)) = app.endpoint_near(x, y) {
+                    let glyph = app.canvas[ey as usize][ex as usize].as_ref().map_or(' ', |cell| cell.ch.glyph());
+                    ctx.print(
+                        ex,
+                        app.screen_y(ey),
+                        Span::styled(glyph.to_string(), Style::default().fg(theme.background).bg(theme.highlight)),
+                    );
+                }
+            }
+
+            // Where each hjkl step would land, for data-grid snap points that
+            // don't line up with the cells around the cursor
+            if app.snap_preview && (app.grid_snap || app.data_snap) && app.mode == AppMode::Drawing {
+                for (dx, dy) in [(-1.0, 0.0), (1.0, 0.0), (0.0, -1.0), (0.0, 1.0)] {
+                    let ((x, y), _) = app.move_target(dx, dy);
+                    let row = y.floor() - app.scroll_y as f64;
+                    let empty = app.canvas.get(y as usize).and_then(|r| r.get(x as usize)).is_some_and(Option::is_none);
+                    if empty && (0.0..app.canvas_height as f64).contains(&row) {
+                        ctx.print(x.floor(), app.screen_y(y), Span::styled("·", Style::default().fg(theme.dim)));
+                    }
+                }
+            }
+
+            // Only draw cursor if it's visible, as a reverse-video cell so it
+            // stands out from drawn points
+            if app.cursor_y >= app.scroll_y as f64
+                && app.cursor_y < (app.scroll_y + app.canvas_height) as f64
+            {
+                let cursor_x = app.cursor_x.floor();
+                let cursor_y = app.screen_y(app.cursor_y);
+                let under_cursor = app.canvas
+                    .get(app.cursor_y as usize)
+                    .and_then(|row| row.get(app.cursor_x as usize))
+                    .and_then(|cell| cell.as_ref())
+                    .map(|cell| cell.ch.glyph());
+
+                // Ghost preview of what space would stamp, never hiding content
+                let ghost = match (under_cursor, app.mode) {
+                    (None, AppMode::Drawing) => Some(app.current_char.glyph()),
+                    _ => None,
+                };
+                if let Some(glyph) = ghost {
+                    ctx.print(
+                        cursor_x,
+                        cursor_y,
+                        Span::styled(
+                            glyph.to_string(),
+                            Style::default().fg(app.current_color).add_modifier(Modifier::DIM),
+                        ),
+                    );
+                }
+
+                if app.cursor_visible() {
+                    let cursor_color = if app.snap_flash_active() { theme.highlight } else { app.mode_color(app.mode) };
+                    ctx.print(
+                        cursor_x,
+                        cursor_y,
+                        Span::styled(
+                            under_cursor.or(ghost).unwrap_or(' ').to_string(),
+                            Style::default().fg(cursor_color).add_modifier(Modifier::REVERSED),
+                        ),
+                    );
+                }
+            }
+
+            // Floating coordinate label two cells from the cursor, flipped to
+            // the other side near the right and top edges
+            if app.show_tooltip {
+                let label = app.get_current_coordinates();
+                let label_width = label.chars().count() as f64;
+                let cursor_x = app.cursor_x.floor();
+                let row = app.cursor_y.floor() - app.scroll_y as f64;
+                let label_x = if cursor_x + 2.0 + label_width <= app.canvas_width as f64 {
+                    cursor_x + 2.0
+                } else {
+                    (cursor_x - 2.0 - label_width).max(0.0)
+                };
+                let label_row = if row >= 1.0 { row - 1.0 } else { row + 1.0 };
+                if label_row >= 0.0 && label_row < app.canvas_height as f64 {
+                    ctx.print(
+                        label_x,
+                        app.canvas_height as f64 - 1.0 - label_row,
+                        Span::styled(label, Style::default().fg(theme.highlight)),
+                    );
+                }
+            }
+
+            // Mode badge in the top-right corner
+            let badge = format!(" {} ", app.mode.name());
+            ctx.print(
+                (app.canvas_width - badge.len()) as f64,
+                app.canvas_height as f64 - 1.0,
+                Span::styled(badge, Style::default().fg(theme.background).bg(app.mode_color(app.mode))),
+            );
+
+            // Calibration overlay: what the visible corners and the origin map to
+            if app.show_calibration {
+                let style = Style::default().fg(theme.background).bg(theme.highlight);
+                let (right, bottom) = (app.canvas_width - 1, app.canvas_height - 1);
+                let corners = [(0, 0, false), (right, 0, true), (0, bottom, false), (right, bottom, true)];
+                for (x, row, align_right) in corners {
+                    let label = app.coordinates_at(x as f64, (app.scroll_y + row) as f64);
+                    let label_x = if align_right {
+                        (x + 1).saturating_sub(label.chars().count())
+                    } else {
+                        x
+                    };
+                    ctx.print(label_x as f64, (bottom - row) as f64, Span::styled(label, style));
+                }
+                let origin_row = app.origin_y.floor() - app.scroll_y as f64;
+                if origin_row >= 0.0 && origin_row < app.canvas_height as f64 {
+                    let label = format!("+{}", app.coordinates_at(app.origin_x, app.origin_y));
+                    ctx.print(app.origin_x.floor(), bottom as f64 - origin_row, Span::styled(label, style));
+                }
+            }
+
             // Draw keyboard grid in selection mode
             if app.mode == AppMode::Selection {
                 for (ch, &(x, y)) in &app.keyboard_grid {
@@ -715,53 +4919,317 @@ fn ui(f: &mut Frame, app: &App) {
                         ctx.print(
                             x as f64,
                             (app.canvas_height - 1 - y) as f64,
-                            Span::styled(ch.to_string(), Style::default().fg(Color::Green)),
+                            Span::styled(ch.to_string(), Style::default().fg(theme.highlight)),
                         );
                     }
                 }
             }
         });
 
-    f.render_widget(canvas_widget, chunks[0]);
+    // Rulers are canvases with the same cell bounds as the drawing, lined up
+    // with its inner area, so labels land on the cells they number
+    let (drawing_area, minimap_area) = if app.show_minimap {
+        let split = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Min(0), Constraint::Length(MINIMAP_WIDTH + 2)].as_ref())
+            .split(main_chunks[0]);
+        (split[0], Some(split[1]))
+    } else {
+        (main_chunks[0], None)
+    };
+    let (drawing_area, preview_area) = if app.show_typst_preview {
+        let split = Layout::default()
+            .direction(Direction::Horizontal)
+            .constraints([Constraint::Percentage(100 - TYPST_PREVIEW_PERCENT), Constraint::Percentage(TYPST_PREVIEW_PERCENT)].as_ref())
+            .split(drawing_area);
+        (split[0], Some(split[1]))
+    } else {
+        (drawing_area, None)
+    };
+
+    let canvas_area = if app.show_rulers {
+        let rows = Layout::default()
+            .direction(Direction::Vertical)
+            .constraints([Constraint::Length(1), Constraint::Min(0)].as_ref())
+            .split(drawing_area);
+        let columns = |area| {
+            Layout::default()
+                .direction(Direction::Horizontal)
+                .constraints([Constraint::Length(RULER_WIDTH), Constraint::Min(0)].as_ref())
+                .split(area)
+        };
+        let (top, body) = (columns(rows[0]), columns(rows[1]));
+
+        let top_ruler = Canvas::default()
+            .x_bounds([0.0, app.canvas_width as f64])
+            .y_bounds([0.0, 1.0])
+            .background_color(theme.background)
+            .paint(|ctx| {
+                for x in (0..app.canvas_width).step_by(5) {
+                    let label = if x % 10 == 0 { x.to_string() } else { "'".to_string() };
+                    ctx.print(x as f64, 0.0, Span::styled(label, Style::default().fg(theme.dim)));
+                }
+            });
+        f.render_widget(top_ruler, top[1].inner(&Margin { horizontal: 1, vertical: 0 }));
+
+        let left_ruler = Canvas::default()
+            .x_bounds([0.0, RULER_WIDTH as f64])
+            .y_bounds([0.0, app.canvas_height as f64])
+            .background_color(theme.background)
+            .paint(|ctx| {
+                let rows = app.canvas_height.min(app.virtual_height - app.scroll_y);
+                for r in 0..rows {
+                    let label = format!("{:>width$}", app.scroll_y + r, width = RULER_WIDTH as usize - 1);
+                    ctx.print(0.0, (app.canvas_height - 1 - r) as f64, Span::styled(label, Style::default().fg(theme.dim)));
+                }
+            });
+        f.render_widget(left_ruler, body[0].inner(&Margin { horizontal: 0, vertical: 1 }));
+        body[1]
+    } else {
+        drawing_area
+    };
+
+    f.render_widget(canvas_widget, canvas_area);
+
+    // Heatmap legend in the canvas's top-right corner
+    if let Some(&max) = app.heat_counts.values().max() {
+        let lines: Vec<TextLine> = heat_legend(max)
+            .into_iter()
+            .map(|(glyph, low, high)| {
+                let range = if low == high { low.to_string() } else { format!("{}-{}", low, high) };
+                TextLine::from(Span::styled(format!("{} {}", glyph, range), Style::default().fg(app.current_color)))
+            })
+            .collect();
+        let width = lines.iter().map(TextLine::width).max().unwrap_or(0).max(7) as u16 + 2;
+        let height = lines.len() as u16 + 2;
+        if canvas_area.width > width + 2 && canvas_area.height > height + 2 {
+            let area = Rect::new(canvas_area.right() - width - 1, canvas_area.y + 1, width, height);
+            let legend = Paragraph::new(lines).block(
+                Block::default()
+                    .title("Density")
+                    .borders(Borders::ALL)
+                    .style(Style::default().fg(theme.accent).bg(theme.background)),
+            );
+            f.render_widget(Clear, area);
+            f.render_widget(legend, area);
+        }
+    }
+
+    // Minimap: shaded by how full each block of cells is, bright where the
+    // block is on screen and dim elsewhere
+    if let Some(area) = minimap_area {
+        let block = Block::default()
+            .title("Map")
+            .borders(Borders::ALL)
+            .style(Style::default().fg(theme.accent).bg(theme.background));
+        let inner = block.inner(area);
+        let (rows_per_line, cols_per_char) = app.minimap_block(inner);
+        let visible = app.scroll_y..app.scroll_y + app.canvas_height;
+        let lines: Vec<TextLine> = (0..app.virtual_height.div_ceil(rows_per_line))
+            .take(inner.height as usize)
+            .map(|line| {
+                let top = line * rows_per_line;
+                let rows = top..(top + rows_per_line).min(app.virtual_height);
+                let in_view = rows.start < visible.end && visible.start < rows.end;
+                let glyphs: String = (0..app.canvas_width.div_ceil(cols_per_char))
+                    .map(|col| {
+                        let left = col * cols_per_char;
+                        let cols = left..(left + cols_per_char).min(app.canvas_width);
+                        let total = rows.len() * cols.len();
+                        let filled = app.canvas[rows.clone()]
+                            .iter()
+                            .map(|row| row[cols.clone()].iter().filter(|cell| cell.is_some()).count())
+                            .sum::<usize>();
+                        match filled * 4 / total.max(1) {
+                            _ if filled == 0 => ' ',
+                            0 => '░',
+                            1 => '▒',
+                            2 => '▓',
+                            _ => '█',
+                        }
+                    })
+                    .collect();
+                let color = if in_view { theme.text } else { theme.dim };
+                TextLine::from(Span::styled(glyphs, Style::default().fg(color)))
+            })
+            .collect();
+        f.render_widget(Paragraph::new(lines).block(block), area);
+    }
+
+    // Typst preview: regenerated every frame so it follows each edit
+    if let Some(area) = preview_area {
+        let block = Block::default()
+            .title(format!("Typst ({})", app.save_path))
+            .borders(Borders::ALL)
+            .style(Style::default().fg(theme.accent).bg(theme.background));
+        let source = Paragraph::new(app.to_typst())
+            .style(Style::default().fg(theme.text))
+            .block(block);
+        f.render_widget(source, area);
+    }
     
     // Render settings popup if in settings mode
     if app.mode == AppMode::Settings {
         let settings_content = format!(
-            "Settings (Press key to toggle):\n\n[a] Axes: {}\n[n] Grid Snap: {}\n[d] Continuous: {}\n\nCoordinate System:\n[1] Cartesian {}\n[2] Polar {}\n[3] Cylindrical {}\n\nPress ? or Esc to close",
+            "Settings (Press key to toggle):\n\n[a] Axes: {}\n[s] Axis style: {}\n[u] Gridlines: {}\n[q] Rulers: {}\n[n] Grid Snap: {}\n[m] Data Snap: {}\n[N] Snap preview: {}\n[o] Origin Snap: {}\n[x] Axis Snap: {}\n[d] Continuous: {}\n[e] Smooth strokes: {}\n[w] Line width: {}\n[r] Draw over: {}\n[c] Coordinates: {}\n[k] Scrolling: {}\n[t] Tooltip: {}\n[b] Blink cursor: {}\n[f] Export as figure: {}\n[E] Exact grid export: {}\n[M] Auto math: {}\n[B] Empty cells: '{}'\n[l] Status line: {}\n[T] Theme: {}\n[i] Import long lines: {}\n[p] Pretty math: {}\n[j] Auto-pair: {} (:pairs)\n[z] Open PDF viewer on r: {}\n\nCoordinate System:\n[1] Cartesian {}\n[2] Polar {}\n[3] Cylindrical {}\n[y] Y axis: {}\n\nAxis Scale:\n[</>] Units/cell: {}\n[[/]] Data step: {}\n[g] Grid offset: ({}, {}) reset\n    :gridoffset x y to set\nCell aspect w:h: {}\n    :aspect ratio to set\nFit to page: {}\n    :page a4|letter|off\n\n[h] Help  [v] About\nPress ? or Esc to close",
             if app.show_axes { "ON" } else { "OFF" },
+            match app.axis_style {
+                AxisStyle::Full => "full",
+                AxisStyle::Ticks => "ticks",
+                AxisStyle::Hidden => "none",
+            },
+            if app.show_gridlines { "ON" } else { "OFF" },
+            if app.show_rulers { "ON" } else { "OFF" },
             if app.grid_snap { "ON" } else { "OFF" },
+            if app.data_snap { "ON" } else { "OFF" },
+            if app.snap_preview { "ON" } else { "OFF" },
+            if app.origin_snap { "ON" } else { "OFF" },
+            if app.axis_snap { "ON" } else { "OFF" },
             if app.continuous_draw { "ON" } else { "OFF" },
+            if app.smooth_strokes { "ON" } else { "OFF" },
+            app.line_width,
+            if app.merge_draw { "merge" } else { "replace" },
+            if app.show_coordinates { "ON" } else { "OFF" },
+            if app.center_cursor { "centered" } else { "edges" },
+            if app.show_tooltip { "ON" } else { "OFF" },
+            if app.blink_cursor { "ON" } else { "OFF" },
+            if app.figure_export { "ON" } else { "OFF" },
+            if app.exact_grid { "ON" } else { "OFF" },
+            if app.auto_math { "ON" } else { "OFF" },
+            app.empty_cell,
+            match app.status_verbosity {
+                StatusVerbosity::Full => "full",
+                StatusVerbosity::Minimal => "minimal",
+                StatusVerbosity::Coordinates => "coordinates",
+            },
+            app.theme.name,
+            if app.import_wrap { "wrap" } else { "clip" },
+            if app.pretty_math { "ON" } else { "OFF" },
+            if app.auto_pair { "ON" } else { "OFF" },
+            if app.spawn_pdf_viewer { "ON" } else { "OFF" },
             if matches!(app.coordinate_system, CoordinateSystem::Cartesian) { "◉" } else { "○" },
             if matches!(app.coordinate_system, CoordinateSystem::Polar) { "◉" } else { "○" },
             if matches!(app.coordinate_system, CoordinateSystem::Cylindrical) { "◉" } else { "○" },
+            if app.y_down { "down" } else { "up" },
+            app.scale_x,
+            app.data_snap_step,
+            app.grid_offset_x,
+            app.grid_offset_y,
+            app.cell_aspect,
+            app.fit_page.map_or("off", PaperSize::typst_name),
         );
         
-        let settings_widget = Paragraph::new(settings_content)
+        let settings_widget = Paragraph::new(settings_content.as_str())
             .block(Block::default()
                 .title("Settings")
                 .borders(Borders::ALL)
-                .style(Style::default().fg(Color::Rgb(100, 149, 237)).bg(Color::Black)))
-            .style(Style::default().bg(Color::Black).fg(Color::White));
-        f.render_widget(settings_widget, chunks[1]);
+                .style(Style::default().fg(theme.accent).bg(theme.background)))
+            .style(Style::default().bg(theme.background).fg(theme.text));
+        let popup = popup_rect(&settings_content, main_chunks[0]);
+        f.render_widget(Clear, popup);
+        f.render_widget(settings_widget, popup);
+    }
+
+    if app.mode == AppMode::Help {
+        let help_widget = Paragraph::new(HELP_TEXT)
+            .block(Block::default()
+                .title("Help")
+                .borders(Borders::ALL)
+                .style(Style::default().fg(theme.accent).bg(theme.background)))
+            .style(Style::default().bg(theme.background).fg(theme.text));
+        f.render_widget(Clear, main_chunks[0]);
+        f.render_widget(help_widget, main_chunks[0]);
+    }
+
+    if app.mode == AppMode::Functions {
+        let mut lines = vec![TextLine::from("")];
+        if app.functions.is_empty() {
+            lines.push(TextLine::from(" No functions yet, add one with :fn "));
+        }
+        for (i, function) in app.functions.iter().enumerate() {
+            let marker = if i == app.function_selected { "▶" } else { " " };
+            lines.push(TextLine::from(vec![
+                Span::raw(format!(" {} {} ", marker, function.brush.glyph())),
+                Span::styled("■ ", Style::default().fg(function.color)),
+                Span::raw(format!("{} ", function.label())),
+            ]));
+        }
+        lines.push(TextLine::from(""));
+        lines.push(TextLine::from(" e edit  c recolor  d delete  b draw all  Esc close "));
+        let width = lines.iter().map(TextLine::width).max().unwrap_or(0) as u16 + 2;
+        let popup = centered_rect(width, lines.len() as u16 + 2, main_chunks[0]);
+        let manager = Paragraph::new(lines)
+            .block(Block::default()
+                .title("Functions")
+                .borders(Borders::ALL)
+                .style(Style::default().fg(theme.accent).bg(theme.background)))
+            .style(Style::default().bg(theme.background).fg(theme.text));
+        f.render_widget(Clear, popup);
+        f.render_widget(manager, popup);
+    }
+
+    if app.mode == AppMode::SaveMenu {
+        let menu_content = format!(
+            "Save as:\n\n[t] typst ({})\n[k] tikz  ({})\n[v] typst snapshot\n    ({})\n\nEsc to cancel",
+            app.save_path,
+            std::path::Path::new(&app.save_path).with_extension("tex").display(),
+            snapshot_path(&app.save_path, SystemTime::now()),
+        );
+        let menu_widget = Paragraph::new(menu_content.as_str())
+            .block(Block::default()
+                .title("Save")
+                .borders(Borders::ALL)
+                .style(Style::default().fg(theme.accent).bg(theme.background)))
+            .style(Style::default().bg(theme.background).fg(theme.text));
+        let popup = popup_rect(&menu_content, main_chunks[0]);
+        f.render_widget(Clear, popup);
+        f.render_widget(menu_widget, popup);
+    }
+
+    if app.mode == AppMode::About {
+        let about_content = format!(
+            "DraVi {}\n\nDraw with vim keys,\nplot functions and\nexport to typst.\n\ntypst: {}\n\n{}\n\n? back to settings\nEsc to close",
+            env!("CARGO_PKG_VERSION"),
+            if app.typst_available { "found on PATH" } else { "NOT FOUND\n(saves won't compile\nto PDF)" },
+            app.canvas_stats(),
+        );
+        let about_widget = Paragraph::new(about_content.as_str())
+            .block(Block::default()
+                .title("About")
+                .borders(Borders::ALL)
+                .style(Style::default().fg(theme.accent).bg(theme.background)))
+            .style(Style::default().bg(theme.background).fg(theme.text));
+        let popup = popup_rect(&about_content, main_chunks[0]);
+        f.render_widget(Clear, popup);
+        f.render_widget(about_widget, popup);
     }
 
     let status_text = match app.mode {
         AppMode::Drawing => {
-            let char_name = match &app.current_char {
-                DrawChar::Point => "point",
-                DrawChar::Horizontal => "horizontal",
-                DrawChar::Vertical => "vertical", 
-                DrawChar::Cross => "cross",
-                DrawChar::DiagRight => "diag-right",
-                DrawChar::DiagLeft => "diag-left",
-                DrawChar::Text(ch) => &format!("text({})", ch),
-            };
-            format!("hjkl:move | space:draw | i:text | g:goto | s:save | x:color | J/K:scroll | ?:settings | q:quit | Drawing: {}", char_name)
+            // Step down to a shorter form rather than letting the terminal cut it mid-word
+            let width = main_chunks[1].width.saturating_sub(2) as usize;
+            let levels = [StatusVerbosity::Full, StatusVerbosity::Minimal, StatusVerbosity::Coordinates];
+            let start = levels.iter().position(|&level| level == app.status_verbosity).unwrap_or(0);
+            let mut texts = levels[start..].iter().map(|&level| app.drawing_status(level));
+            let mut text = texts.next().unwrap_or_default();
+            for shorter in texts {
+                if text.chars().count() <= width {
+                    break;
+                }
+                text = shorter;
+            }
+            text
         }
         AppMode::Selection => "Selection mode - press any key to jump to that position, Esc to cancel".to_string(),
-        AppMode::ColorSelection => format!("Color (hex): {} | Enter to apply, Esc to cancel", app.color_input),
-        AppMode::TypstInput => format!("Typst mode: {} | Enter to place, use $ for math, Backspace to edit, Esc to exit", app.text_buffer),
+        AppMode::ColorSelection => format!("Color (hex, or from>to for a gradient): {} | Enter to apply, Esc to cancel", app.color_input),
+        AppMode::TypstInput => format!("Typst mode [{}]: {}▏{} | Enter to place, use $ for math, Ctrl+b/t/u: bold/italic/underline, Esc to exit", app.text_attrs.label(), app.text_buffer, app.pending_closers),
         AppMode::Settings => "Settings mode - use keys shown in popup to toggle options, ? or Esc to close".to_string(),
+        AppMode::Command => format!(":{}", app.command_input),
+        AppMode::About => "About - ? for settings, Esc to return to drawing".to_string(),
+        AppMode::SaveMenu => "Save - t: typst, k: tikz, v: timestamped typst, Esc to cancel".to_string(),
+        AppMode::Functions => "Functions - j/k: select, e: edit, c: take current color and brush, d: delete, b: draw all into canvas, Esc to close".to_string(),
+        AppMode::Help => "Help - ? for settings, Esc to return to drawing".to_string(),
+        AppMode::Polyline => format!("Polyline: {} vertices | hjkl:move | space/Enter:add vertex | a:45° snap {} | e:end snap {} | c:close polygon | F:close+fill | Esc:finish", app.polyline.len(), if app.angle_snap { "ON" } else { "OFF" }, if app.endpoint_snap { "ON" } else { "OFF" }),
         AppMode::PdfRender => "PDF Render mode - viewing compiled PDF. Press r or Esc to return to drawing".to_string(),
         AppMode::CoordinateInput => {
             let hint = match app.coordinate_system {
@@ -769,59 +5237,714 @@ fn ui(f: &mut Frame, app: &App) {
                 CoordinateSystem::Polar => "r,θ(deg)",
                 CoordinateSystem::Cylindrical => "ρ,θ(deg),z",
             };
-            format!("Go to ({}): {} | Enter to move, Esc to cancel", hint, app.coordinate_input)
+            format!("Go to ({}) or draw ({0} - {0}), @ for relative, from {}: {} | Enter to move, Esc to cancel", hint, app.get_current_coordinates(), app.coordinate_input)
         }
     };
 
     let status = Paragraph::new(status_text)
         .block(Block::default()
             .borders(Borders::ALL)
-            .style(Style::default().fg(Color::Rgb(100, 149, 237)).bg(Color::Black)))
-        .style(Style::default().bg(Color::Black).fg(Color::White));
+            .style(Style::default().fg(theme.accent).bg(theme.background)))
+        .style(Style::default().bg(theme.background).fg(theme.text));
     f.render_widget(status, main_chunks[1]);
+
+    minimap_area
 }
 
 fn run_app(terminal: &mut Terminal<CrosstermBackend<io::Stdout>>, mut app: App) -> Result<()> {
+    let mut needs_redraw = true;
+    let mut last_cursor_state = (app.cursor_visible(), app.snap_flash_active());
+    let mut minimap_area = None;
     loop {
-        terminal.draw(|f| ui(f, &app))?;
+        if needs_redraw {
+            app.refresh_render_cache();
+            terminal.draw(|f| minimap_area = ui(f, &app))?;
+            needs_redraw = false;
+        }
+
+        if app.advance_replay() {
+            needs_redraw = true;
+        }
+        if app.poll_compile() {
+            needs_redraw = true;
+        }
+
+        // Only the blink phase and the snap flash change while idle
+        let cursor_state = (app.cursor_visible(), app.snap_flash_active());
+        if cursor_state != last_cursor_state {
+            last_cursor_state = cursor_state;
+            needs_redraw = true;
+        }
 
         if event::poll(Duration::from_millis(16))? {
-            if let Event::Key(key) = event::read()? {
-                app.handle_key(key);
+            // Handle everything already queued before the next redraw, so a
+            // held key doesn't fall further behind with every repeat
+            loop {
+                match event::read()? {
+                    Event::Key(key) => {
+                        app.handle_key(key);
+                        needs_redraw = true;
+                    }
+                    Event::Resize(_, _) => needs_redraw = true,
+                    Event::Mouse(mouse) if mouse.kind == MouseEventKind::Down(MouseButton::Left) => {
+                        if let Some(area) = minimap_area.filter(|area: &Rect| area.x <= mouse.column && mouse.column < area.x + area.width) {
+                            app.click_minimap(area, mouse.row);
+                            needs_redraw = true;
+                        }
+                    }
+                    _ => {}
+                }
+                if app.should_quit || !event::poll(Duration::ZERO)? {
+                    break;
+                }
             }
         }
 
         if app.should_quit {
+            app.remember_brush();
+            app.finish_compiles();
             break;
         }
     }
     Ok(())
 }
 
+// Last-used brush lives in $XDG_CONFIG_HOME/dravi/brush (or ~/.config/dravi/brush)
+fn brush_state_path() -> Option<std::path::PathBuf> {
+    let config_dir = std::env::var_os("XDG_CONFIG_HOME")
+        .map(std::path::PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| std::path::Path::new(&home).join(".config")))?;
+    Some(config_dir.join("dravi").join("brush"))
+}
+
+fn latex_escape(text: &str) -> String {
+    let mut out = String::new();
+    for ch in text.chars() {
+        match ch {
+            '\\' => out.push_str("\\textbackslash{}"),
+            '~' => out.push_str("\\textasciitilde{}"),
+            '^' => out.push_str("\\textasciicircum{}"),
+            '&' | '%' | '$' | '#' | '_' | '{' | '}' => {
+                out.push('\\');
+                out.push(ch);
+            }
+            _ => out.push(ch),
+        }
+    }
+    out
+}
+
+// Plain text for a typst content block: markup characters are backslash-escaped
+// so e.g. `]` can't close the block early and `//` doesn't start a comment
+fn typst_escape(text: &str) -> String {
+    let mut out = String::new();
+    for ch in text.chars() {
+        if matches!(ch, '\\' | '#' | '[' | ']' | '$' | '*' | '_' | '`' | '<' | '>' | '@' | '=' | '-' | '+' | '/' | '~') {
+            out.push('\\');
+        }
+        out.push(ch);
+    }
+    out
+}
+
+// End point on the 0/45/90° ray from (x0, y0) closest to (x1, y1), in whole
+// cells so diagonals come out as clean runs of / or \
+fn constrain_45(x0: f64, y0: f64, x1: f64, y1: f64) -> (f64, f64) {
+    let (dx, dy) = ((x1 - x0).round(), (y1 - y0).round());
+    let octant = (dy.atan2(dx) / std::f64::consts::FRAC_PI_4).round();
+    let (ux, uy) = ((octant * std::f64::consts::FRAC_PI_4).cos().round(), (octant * std::f64::consts::FRAC_PI_4).sin().round());
+    // Project onto the direction; diagonal steps move one cell on each axis
+    let steps = ((dx * ux + dy * uy) / (ux * ux + uy * uy)).round();
+    (x0 + ux * steps, y0 + uy * steps)
+}
+
+// One of eight arrows closest to the direction (x right, y up); a dot for zero
+fn arrow_glyph(x: f64, y: f64) -> char {
+    if x == 0.0 && y == 0.0 {
+        return '·';
+    }
+    let octant = ((y.atan2(x).to_degrees() + 360.0 + 22.5) / 45.0) as usize % 8;
+    ['→', '↗', '↑', '↖', '←', '↙', '↓', '↘'][octant]
+}
+
+// Line glyph closest to the direction (x right, y up); None for zero
+fn direction_glyph(x: f64, y: f64) -> Option<DrawChar> {
+    if x == 0.0 && y == 0.0 {
+        return None;
+    }
+    let octant = ((y.atan2(x).to_degrees() + 360.0 + 22.5) / 45.0) as usize % 4;
+    Some([DrawChar::Horizontal, DrawChar::DiagRight, DrawChar::Vertical, DrawChar::DiagLeft][octant].clone())
+}
+
+// Linear RGB blend; non-RGB colors can't be blended and stay at `start`
+fn lerp_color(start: Color, end: Color, t: f64) -> Color {
+    match (start, end) {
+        (Color::Rgb(r0, g0, b0), Color::Rgb(r1, g1, b1)) => {
+            let mix = |a: u8, b: u8| (a as f64 + (b as f64 - a as f64) * t).round() as u8;
+            Color::Rgb(mix(r0, r1), mix(g0, g1), mix(b0, b1))
+        }
+        _ => start,
+    }
+}
+
+// Uniform Catmull-Rom spline between p1 and p2 at t in [0, 1]
+fn catmull_rom(p0: (f64, f64), p1: (f64, f64), p2: (f64, f64), p3: (f64, f64), t: f64) -> (f64, f64) {
+    let (t2, t3) = (t * t, t * t * t);
+    let blend = |a: f64, b: f64, c: f64, d: f64| {
+        0.5 * (2.0 * b + (c - a) * t + (2.0 * a - 5.0 * b + 4.0 * c - d) * t2 + (3.0 * b - a - 3.0 * c + d) * t3)
+    };
+    (blend(p0.0, p1.0, p2.0, p3.0), blend(p0.1, p1.1, p2.1, p3.1))
+}
+
+// Raw mode and the alternate screen, undone on drop even if setup fails
+// halfway or we leave early through `?`
+struct TerminalGuard;
+
+impl TerminalGuard {
+    fn new() -> Result<TerminalGuard> {
+        enable_raw_mode()?;
+        // From here on Drop cleans up, including after a failed execute!
+        let guard = TerminalGuard;
+        execute!(io::stdout(), EnterAlternateScreen, EnableMouseCapture)?;
+        Ok(guard)
+    }
+}
+
+impl Drop for TerminalGuard {
+    fn drop(&mut self) {
+        restore_terminal();
+    }
+}
+
+// Safe to call more than once; a panic restores here and again while unwinding
+fn restore_terminal() {
+    let _ = disable_raw_mode();
+    let _ = execute!(io::stdout(), LeaveAlternateScreen, DisableMouseCapture, crossterm::cursor::Show);
+}
+
+// Render a saved drawing to stdout without entering the TUI
+fn render_headless(path: &str) -> Result<()> {
+    let contents = std::fs::read_to_string(path)?;
+    let mut app = App::from_env();
+    app.load_drawing(&contents);
+    print!("{}", app.to_ascii());
+    Ok(())
+}
+
 fn main() -> Result<()> {
-    // Setup terminal
-    enable_raw_mode()?;
-    let mut stdout = io::stdout();
-    execute!(stdout, EnterAlternateScreen, EnableMouseCapture)?;
-    let backend = CrosstermBackend::new(stdout);
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    match args.first().map(String::as_str) {
+        Some("--version") | Some("-V") => {
+            println!("dravi {}", env!("CARGO_PKG_VERSION"));
+            println!("typst: {}", if typst_on_path() { "found on PATH" } else { "not found" });
+            return Ok(());
+        }
+        Some("--print") => return render_headless("drawing.typ"),
+        Some("--render-only") => {
+            let path = args.get(1).ok_or("--render-only requires a file argument")?;
+            return render_headless(path);
+        }
+        _ => {}
+    }
+
+    // Create app before touching the terminal so argument errors print normally
+    let mut app = App::from_env();
+    // The brush remembered from the last session, unless DRAVI_BRUSH picks one
+    if std::env::var_os("DRAVI_BRUSH").is_none() {
+        app.restore_brush();
+    }
+    if let Some(pos) = args.iter().position(|arg| arg == "--brush") {
+        let name = args.get(pos + 1).ok_or("--brush requires a brush name")?;
+        app.set_brush_from_config(name);
+    }
+    if let Some(pos) = args.iter().position(|arg| arg == "--theme") {
+        let name = args.get(pos + 1).ok_or("--theme requires a theme name")?;
+        app.set_theme_from_config(name);
+    }
+    // `cat diagram.txt | dravi` starts from the piped text; keys still come
+    // from the terminal since crossterm reads /dev/tty when stdin is a pipe
+    if !io::stdin().is_terminal() {
+        let mut piped = String::new();
+        io::stdin().read_to_string(&mut piped)?;
+        if !piped.is_empty() {
+            let rows = app.import_text(&piped);
+            app.status_message = Some(format!("Read {} rows from stdin", rows));
+        }
+    }
+    if let Some(pos) = args.iter().position(|arg| arg == "--script") {
+        let path = args.get(pos + 1).ok_or("--script requires a file argument")?;
+        let script = std::fs::read_to_string(path)?;
+        app.run_script(&script).map_err(|err| format!("{}: {}", path, err))?;
+        if args.iter().any(|arg| arg == "--headless") {
+            print!("{}", app.to_ascii());
+            return Ok(());
+        }
+    }
+
+    // Panic messages would be lost on the alternate screen, so restore the
+    // terminal before the default hook prints them
+    let previous_hook: std::sync::Arc<dyn Fn(&std::panic::PanicHookInfo) + Send + Sync> = std::panic::take_hook().into();
+    let hook = previous_hook.clone();
+    std::panic::set_hook(Box::new(move |info| {
+        restore_terminal();
+        hook(info);
+    }));
+
+    // Setup terminal; the guard restores it however we leave this function
+    let guard = TerminalGuard::new()?;
+    let backend = CrosstermBackend::new(io::stdout());
     let mut terminal = Terminal::new(backend)?;
 
-    // Create app and run it
-    let app = App::new();
     let res = run_app(&mut terminal, app);
 
-    // Restore terminal
-    disable_raw_mode()?;
-    execute!(
-        terminal.backend_mut(),
-        LeaveAlternateScreen,
-        DisableMouseCapture
-    )?;
-    terminal.show_cursor()?;
+    // Restore terminal before reporting errors so they land on the normal screen
+    drop(guard);
+    let _ = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| previous_hook(info)));
 
     if let Err(err) = res {
         println!("{:?}", err)
     }
 
     Ok(())
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn goto(app: &mut App, input: &str) {
+        app.coordinate_input = input.to_string();
+        app.parse_and_move_to_coordinate();
+    }
+
+    #[test]
+    fn origin_reports_zero_in_every_coordinate_system() {
+        let cases = [
+            (CoordinateSystem::Cartesian, "0,0", "(0.00, 0.00)"),
+            (CoordinateSystem::Polar, "0,0", "(r:0.00, θ:0.00°)"),
+            (CoordinateSystem::Cylindrical, "0,0,0", "(ρ:0.00, θ:0.00°, z:0.00)"),
+        ];
+        for (system, input, expected) in cases {
+            let mut app = App::new();
+            app.origin_x = 12.25;
+            app.origin_y = 7.5;
+            app.coordinate_system = system;
+            goto(&mut app, input);
+            assert_eq!((app.cursor_x, app.cursor_y), (12.25, 7.5));
+            assert_eq!(app.get_current_coordinates(), expected);
+        }
+    }
+
+    #[test]
+    fn origin_cell_matches_cursor_cell() {
+        let mut app = App::new();
+        app.origin_x = 12.75;
+        app.origin_y = 7.5;
+        app.scroll_y = 3;
+        goto(&mut app, "0,0");
+        assert_eq!(app.screen_y(app.origin_y), app.screen_y(app.cursor_y));
+        assert_eq!(app.screen_y(app.origin_y), app.canvas_height as f64 - 1.0 - 4.0);
+    }
+
+    #[test]
+    fn dirty_row_refresh_matches_full_rebuild() {
+        let mut app = App::new();
+        for y in 0..app.virtual_height {
+            for x in 0..app.canvas_width {
+                app.set_cell(x, y, DrawChar::Cross);
+            }
+        }
+        app.refresh_render_cache();
+        app.set_cell(3, 5, DrawChar::Point);
+        app.clear_cell(7, 9);
+        app.refresh_render_cache();
+
+        let incremental = app.render_cache.clone();
+        app.render_cache_key = None;
+        app.refresh_render_cache();
+        assert_eq!(incremental, app.render_cache);
+    }
+
+    #[test]
+    fn window_maps_onto_the_visible_canvas() {
+        let mut app = App::new();
+        app.scroll_y = 5;
+        app.set_window("-2,6,-1,3");
+        let bottom = (app.scroll_y + app.canvas_height - 1) as f64;
+        let right = app.canvas_width as f64 - 1.0;
+        let close = |(x, y): (f64, f64), (ex, ey): (f64, f64)| (x - ex).abs() < 1e-9 && (y - ey).abs() < 1e-9;
+        assert!(close(app.cell_to_data(0.0, app.scroll_y as f64), (-2.0, 3.0)));
+        assert!(close(app.cell_to_data(right, bottom), (6.0, -1.0)));
+    }
+
+    #[test]
+    fn snapshot_path_appends_a_utc_timestamp() {
+        let at = UNIX_EPOCH + Duration::from_secs(1_704_110_400);
+        assert_eq!(snapshot_path("drawing.typ", at), "drawing-20240101-120000.typ");
+        assert_eq!(snapshot_path("out/fig", at + Duration::from_secs(61)), "out/fig-20240101-120101.typ");
+    }
+
+    #[test]
+    fn grid_snap_puts_line_endpoints_on_grid_cells() {
+        let mut app = App::new();
+        app.grid_snap = true;
+        app.draw_line(1.6, 2.4, 10.7, 2.4);
+        let row = &app.canvas[2];
+        assert!(row[1].is_none());
+        assert!(row[2].is_some());
+        assert!(row[11].is_some());
+        assert!(row[12].is_none());
+    }
+
+    #[test]
+    fn continuous_stroke_is_one_undo_step() {
+        let mut app = App::new();
+        app.toggle_continuous_draw();
+        for _ in 0..5 {
+            app.draw_move(1.0, 0.0);
+        }
+        app.draw_char();
+        app.draw_move(0.0, 1.0);
+        // The stroke, then the stroke continued after the stamp
+        assert_eq!(app.undo_stack.len(), 2);
+        app.undo();
+        app.undo();
+        assert!(app.canvas.iter().flatten().all(Option::is_none));
+    }
+
+    #[test]
+    fn undoing_clear_restores_typed_text() {
+        let mut app = App::new();
+        app.mode = AppMode::TypstInput;
+        for ch in "hi\r".chars() {
+            let code = if ch == '\r' { KeyCode::Enter } else { KeyCode::Char(ch) };
+            app.handle_key(KeyEvent::new(code, KeyModifiers::NONE));
+        }
+        assert_eq!(app.typst_content.len(), 1);
+        app.push_undo();
+        app.clear_canvas();
+        assert!(app.typst_content.is_empty());
+        app.undo();
+        assert_eq!(app.typst_content.len(), 1);
+        // Undoing the text entry itself drops it from the export too
+        app.undo();
+        assert!(app.typst_content.is_empty());
+    }
+
+    #[test]
+    fn scanline_fill_stays_on_the_canvas() {
+        let far = [(-1e9, -1e9), (1e9, -1e9), (1e9, 1e9), (-1e9, 1e9)];
+        let cells = scanline_fill(&far, 4, 3);
+        assert_eq!(cells.len(), 12);
+        assert!(cells.iter().all(|&(x, y)| (0..4).contains(&x) && (0..3).contains(&y)));
+    }
+
+    #[test]
+    fn far_off_lines_are_clipped_to_the_canvas() {
+        let drawn = |app: &App| app.canvas.iter().flatten().flatten().count();
+        let mut app = App::new();
+        app.draw_line(-1e12, 0.0, 1e12, 0.0);
+        assert_eq!(drawn(&app), app.canvas_width);
+        app.draw_line(f64::NAN, 1.0, 3.0, 1.0);
+        assert_eq!(drawn(&app), app.canvas_width);
+        assert!(clip_segment((-5.0, -5.0), (-1.0, -9.0), (0.0, 0.0), (4.0, 4.0)).is_none());
+    }
+
+    #[test]
+    fn script_lines_off_the_canvas_are_rejected() {
+        let mut app = App::new();
+        assert!(app.run_script("line -1e12 0 1e12 0").is_ok());
+        assert!(app.run_script("line 0 0 inf 0").is_err());
+        assert!(app.run_script("line 1e9 1e9 2e9 2e9").is_err());
+        assert!(app.run_script("point 0 0").is_ok());
+        assert_eq!(app.run_script("point 1e9 0"), Err("line 1: point lies off the canvas".to_string()));
+        assert!(app.run_script("text -3 0 \"abcd\"").is_ok());
+        assert_eq!(app.run_script("point 0 0\ntext 0 1e9 \"a\""), Err("line 2: text lies off the canvas".to_string()));
+    }
+
+    #[test]
+    fn relative_endpoints_are_validated_before_drawing() {
+        let drawn = |app: &App| app.canvas.iter().flatten().flatten().count();
+        let mut app = App::new();
+        goto(&mut app, "1e308,0 - @1e308,0");
+        assert_eq!(drawn(&app), 0);
+        assert!(app.undo_stack.is_empty());
+        goto(&mut app, "1e6,1e6 - @1,1");
+        assert_eq!(drawn(&app), 0);
+        goto(&mut app, "0,0 - @2,0");
+        assert!(drawn(&app) > 0);
+    }
+
+    #[test]
+    fn oversized_polygons_are_rejected() {
+        let mut app = App::new();
+        app.draw_regular_polygon("100000 2");
+        assert!(app.undo_stack.is_empty());
+        app.draw_regular_polygon("6 1e12");
+        assert!(app.undo_stack.is_empty());
+        app.draw_regular_polygon("6 3");
+        assert_eq!(app.undo_stack.len(), 1);
+    }
+
+    #[test]
+    fn huge_tables_are_clamped_to_the_canvas() {
+        let mut app = App::new();
+        app.stamp_table(&format!("{} 2", usize::MAX));
+        assert!(app.undo_stack.is_empty());
+        app.stamp_table("1000000 1000000");
+        let drawn = app.canvas.iter().flatten().flatten().count();
+        assert!(drawn > 0 && drawn <= app.canvas_width * app.virtual_height);
+
+        // Huge cells: only the top-left corner of the table is on the canvas
+        let mut app = App::new();
+        app.stamp_table(&format!("1 1 {} {}", usize::MAX, usize::MAX));
+        let (x, y) = (app.cursor_x as usize, app.cursor_y as usize);
+        assert!(matches!(app.canvas[y][x], Some(CanvasCell { ch: DrawChar::Cross, .. })));
+        assert!(matches!(app.canvas[y][x + 1], Some(CanvasCell { ch: DrawChar::Horizontal, .. })));
+        assert!(matches!(app.canvas[y + 1][x], Some(CanvasCell { ch: DrawChar::Vertical, .. })));
+    }
+
+    #[test]
+    fn region_exports_are_cut_to_the_canvas() {
+        let mut app = App::new();
+        app.set_cell(app.canvas_width - 1, 0, DrawChar::Point);
+        let cropped = app.with_region(app.canvas_width - 1, 0, usize::MAX, 1, |app| app.canvas.clone());
+        assert_eq!(cropped.len(), 1);
+        assert_eq!(cropped[0].len(), 1);
+        assert!(cropped[0][0].is_some());
+
+        app.cursor_x = (app.canvas_width - 1) as f64;
+        app.export_region("0 3 /nonexistent/out.txt");
+        assert_eq!(app.status_message.as_deref(), Some("export error: the region is empty"));
+    }
+
+    #[test]
+    fn heatmaps_are_saved_cleared_and_undone() {
+        let path = std::env::temp_dir().join(format!("dravi-heat-{}.csv", std::process::id()));
+        std::fs::write(&path, "x,y\n0,0\n0,0\n1,0\n").unwrap();
+        let mut app = App::new();
+        app.load_heatmap(path.to_str().unwrap());
+        assert!(app.has_drawing());
+        assert_eq!(app.heat_counts.len(), 2);
+
+        app.undo();
+        assert!(app.heat_counts.is_empty() && !app.has_drawing());
+
+        app.load_heatmap(path.to_str().unwrap());
+        app.load_heatmap("clear");
+        assert!(app.heat_counts.is_empty() && !app.has_drawing());
+
+        // Nothing read leaves nothing to undo
+        let undo_steps = app.undo_stack.len();
+        std::fs::write(&path, "x,y\n").unwrap();
+        app.load_heatmap(path.to_str().unwrap());
+        assert_eq!(app.undo_stack.len(), undo_steps);
+        let _ = std::fs::remove_file(&path);
+    }
+
+    // cargo test --release dirty_row_redraw -- --ignored --nocapture
+    #[test]
+    #[ignore]
+    fn dirty_row_redraw_beats_a_full_repaint() {
+        let mut app = App::new();
+        app.canvas_height = 200;
+        for y in 0..200 {
+            for x in 0..app.canvas_width {
+                app.set_cell(x, y, DrawChar::Cross);
+            }
+        }
+        app.refresh_render_cache();
+
+        let frames = 500;
+        let started = Instant::now();
+        for i in 0..frames {
+            app.set_cell(i % app.canvas_width, i % 200, DrawChar::Point);
+            app.refresh_render_cache();
+        }
+        let dirty = started.elapsed();
+        let started = Instant::now();
+        for i in 0..frames {
+            app.set_cell(i % app.canvas_width, i % 200, DrawChar::Cross);
+            app.render_cache_key = None;
+            app.refresh_render_cache();
+        }
+        let full = started.elapsed();
+        println!("{} frames on 200 full rows: dirty rows {:?}, full repaint {:?}", frames, dirty, full);
+        assert!(dirty < full);
+    }
+
+    #[test]
+    fn rotating_by_a_non_finite_angle_is_rejected() {
+        let mut app = App::new();
+        app.set_cell(3, 2, DrawChar::Cross);
+        app.run_command("rotate nan");
+        assert!(app.undo_stack.is_empty());
+        assert_eq!(app.canvas.iter().flatten().flatten().count(), 1);
+        assert_eq!(app.status_message.as_deref(), Some("rotate error: expected an angle in degrees"));
+    }
+
+    #[test]
+    fn grid_offset_needs_two_finite_numbers() {
+        let mut app = App::new();
+        for bad in ["1,x,2", "nan 0", "1 inf"] {
+            app.run_command(&format!("gridoffset {}", bad));
+            assert_eq!((app.grid_offset_x, app.grid_offset_y), (0.0, 0.0));
+        }
+        app.run_command("gridoffset 1,2");
+        assert_eq!((app.grid_offset_x, app.grid_offset_y), (1.0, 2.0));
+    }
+
+    #[test]
+    fn windows_outside_the_scale_range_are_rejected() {
+        let mut app = App::new();
+        let before = (app.origin_x, app.origin_y, app.scale_x, app.scale_y);
+        for window in ["-1e308,1e308,0,1", "0,1e-9,0,1", "0,nan,0,1"] {
+            app.set_window(window);
+            assert_eq!((app.origin_x, app.origin_y, app.scale_x, app.scale_y), before);
+        }
+        app.set_window("-10,10,-5,5");
+        assert!(view_in_range((app.origin_x, app.origin_y, app.scale_x, app.scale_y)));
+    }
+
+    #[test]
+    fn calibrations_outside_the_scale_range_are_rejected() {
+        let mut app = App::new();
+        let before = (app.origin_x, app.origin_y, app.scale_x, app.scale_y);
+        app.calibrate("0,0");
+        app.cursor_x += 1.0;
+        app.cursor_y -= 1.0;
+        app.calibrate("1e300,1e300");
+        assert_eq!((app.origin_x, app.origin_y, app.scale_x, app.scale_y), before);
+        assert!(app.status_message.as_deref().is_some_and(|message| message.starts_with("calibrate error")));
+    }
+
+    #[test]
+    fn figure_caption_is_escaped() {
+        let mut app = App::new();
+        app.figure_export = true;
+        app.figure_caption = r"a] #b \c".to_string();
+        assert!(app.to_typst().contains(r"caption: [a\] \#b \\c],"));
+    }
+
+    #[test]
+    fn calibration_maps_both_points_to_their_values() {
+        let mut app = App::new();
+        app.cursor_x = 10.0;
+        app.cursor_y = 30.0;
+        app.calibrate("0,0");
+        app.cursor_x = 50.0;
+        app.cursor_y = 10.0;
+        app.calibrate("10,5");
+        assert_eq!(app.cell_to_data(10.0, 30.0), (0.0, 0.0));
+        assert_eq!(app.cell_to_data(50.0, 10.0), (10.0, 5.0));
+    }
+
+    #[test]
+    fn y_down_flips_the_vertical_axis() {
+        for (y_down, row) in [(false, 7.0), (true, 13.0)] {
+            let mut app = App::new();
+            app.origin_x = 10.0;
+            app.origin_y = 10.0;
+            app.y_down = y_down;
+            goto(&mut app, "2,3");
+            assert_eq!((app.cursor_x, app.cursor_y), (12.0, row));
+            assert_eq!(app.get_current_coordinates(), "(2.0, 3.0)");
+            assert_eq!(app.cell_to_data(12.0, row), (2.0, 3.0));
+        }
+    }
+
+    #[test]
+    fn single_equations_become_inline_math() {
+        assert_eq!(typst_markup("x = 1 + 2"), "$x = 1 + 2$");
+        assert_eq!(typst_markup("a/b = c"), "$a/b = c$");
+        // No operator, two equals signs, or math already marked up
+        assert_eq!(typst_markup("title = draft"), "title = draft");
+        assert_eq!(typst_markup("a = b + c = d"), "a = b + c = d");
+        assert_eq!(typst_markup("$x = 1 + 2$ holds"), "$x = 1 + 2$ holds");
+        assert_eq!(typst_markup("well-known"), "well-known");
+    }
+
+    #[test]
+    fn only_drawn_glyphs_make_an_ascii_block() {
+        let mut app = App::new();
+        app.set_cell(0, 0, DrawChar::Text('a'));
+        app.typst_content.push(TypstLine { text: "a".to_string(), attrs: TextAttrs::default() });
+        assert!(!app.to_typst().contains("```"));
+
+        app.set_cell(1, 0, DrawChar::Horizontal);
+        assert!(app.to_typst().contains("```\na-\n```"));
+        assert_eq!(app.to_ascii(), "a-\n");
+    }
+
+    #[test]
+    fn heat_legend_ranges_match_the_glyph_levels() {
+        assert_eq!(heat_legend(1), vec![('█', 1, 1)]);
+        for max in [1, 2, 3, 7, 8, 100] {
+            let legend = heat_legend(max);
+            assert_eq!(legend.first().map(|l| l.1), Some(1));
+            assert_eq!(legend.last().map(|l| (l.0, l.2)), Some(('█', max)));
+            for (glyph, low, high) in legend {
+                for count in low..=high {
+                    assert_eq!(HEAT_GLYPHS[heat_level(count, max)], glyph);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn macro_replays_recorded_keys_without_recursing() {
+        let press = |app: &mut App, ch: char| app.handle_key(KeyEvent::new(KeyCode::Char(ch), KeyModifiers::NONE));
+        let mut app = App::new();
+        let start = app.cursor_x;
+        // Record: move right, draw, then a nested @ that must not recurse
+        for ch in ['Q', 'l', ' ', '@', 'Q'] {
+            press(&mut app, ch);
+        }
+        assert_eq!(app.macro_keys.len(), 3);
+        app.play_macro(4);
+        assert_eq!(app.cursor_x, start + 5.0);
+        let drawn = app.canvas[app.cursor_y as usize].iter().filter(|cell| cell.is_some()).count();
+        assert_eq!(drawn, 5);
+    }
+
+    #[test]
+    fn empty_cell_placeholder_round_trips_through_typst() {
+        let mut app = App::new();
+        app.empty_cell = '.';
+        app.set_cell(0, 0, DrawChar::Horizontal);
+        app.set_cell(3, 1, DrawChar::Vertical);
+        assert_eq!(app.to_ascii(), "-...\n...|\n");
+
+        let saved = app.to_typst();
+        let mut loaded = App::new();
+        loaded.load_drawing(&saved);
+        assert!(loaded.canvas[0][1].is_none());
+        assert!(loaded.canvas[0][0].is_some() && loaded.canvas[1][3].is_some());
+
+        // A drawn '.' would be erased on reload, so blanks go out as spaces
+        app.set_cell(1, 1, DrawChar::Text('.'));
+        let mut loaded = App::new();
+        loaded.load_drawing(&app.to_typst());
+        assert!(loaded.canvas[1][1].is_some());
+    }
+
+    #[test]
+    fn ctrl_combinations_do_not_fall_through_to_plain_keys() {
+        let drawn = |app: &App| app.canvas.iter().flatten().flatten().count();
+        let mut app = App::new();
+        app.handle_key(KeyEvent::new(KeyCode::Char(' '), KeyModifiers::NONE));
+        assert_eq!(drawn(&app), 1);
+
+        // Ctrl+c is not c (clear canvas), and a key release doesn't stamp again
+        app.handle_key(KeyEvent::new(KeyCode::Char('c'), KeyModifiers::CONTROL));
+        let mut release = KeyEvent::new(KeyCode::Char(' '), KeyModifiers::NONE);
+        release.kind = KeyEventKind::Release;
+        app.handle_key(release);
+        assert_eq!(drawn(&app), 1);
+        assert_eq!(app.undo_stack.len(), 1);
+
+        app.handle_key(KeyEvent::new(KeyCode::Char('z'), KeyModifiers::CONTROL));
+        assert_eq!(drawn(&app), 0);
+    }
+}